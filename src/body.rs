@@ -0,0 +1,363 @@
+use core::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+use crate::headers::{find_crlf, Headers};
+use crate::trailers;
+
+const READ_BUFFER_SIZE: usize = 1024;
+// a size line is a few hex digits plus extensions nobody should need kilobytes for
+const MAX_CHUNK_LINE: usize = 4096;
+// 16 hex digits already covers every u64
+const MAX_CHUNK_SIZE_DIGITS: usize = 16;
+const MAX_TRAILER_SIZE: usize = 16 * 1024;
+
+/// Where a chunked body's trailer fields end up once it's been read to the
+/// end. Shared so they can be picked up after the reader is gone.
+pub type TrailerSlot = Arc<Mutex<Option<Headers>>>;
+
+/// How the end of a request body is found on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    Length(u64),
+    Chunked,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecodeState {
+    Length(u64),
+    ChunkSize,
+    ChunkData(u64),
+    ChunkDataEnd,
+    Trailers,
+    Done,
+}
+
+/// What a `BodyDecoder` can make of the raw bytes it has so far.
+#[derive(Debug, Clone)]
+pub enum BodyEvent {
+    Data(Bytes),
+    /// The trailer section of a chunked body, fields not allowed there left out.
+    Trailers(Headers),
+    End,
+}
+
+/// Undoes a request body's framing without doing any I/O: raw bytes go in
+/// through `extend` and come back out of `next_event` decoded.
+#[derive(Debug, Clone)]
+pub struct BodyDecoder {
+    raw: BytesMut,
+    state: DecodeState,
+    trailers: Headers,
+    trailer_size: usize,
+}
+
+impl BodyDecoder {
+    pub fn new(framing: Framing) -> Self {
+        let state = match framing {
+            Framing::Length(0) | Framing::None => DecodeState::Done,
+            Framing::Length(n) => DecodeState::Length(n),
+            Framing::Chunked => DecodeState::ChunkSize,
+        };
+        Self { raw: BytesMut::new(), state, trailers: Headers::new(), trailer_size: 0 }
+    }
+
+    pub fn extend(&mut self, raw: &[u8]) {
+        self.raw.extend_from_slice(raw);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == DecodeState::Done
+    }
+
+    /// Bytes received past the end of the body.
+    pub fn into_leftover(self) -> Vec<u8> {
+        self.raw.to_vec()
+    }
+
+    // finds the CRLF ending the next line, refusing to wait forever for one
+    fn line_end(&self, limit: usize) -> io::Result<Option<usize>> {
+        match find_crlf(&self.raw, 0) {
+            Some(end) if end <= limit => Ok(Some(end)),
+            None if self.raw.len() <= limit => Ok(None),
+            _ => Err(invalid_data("chunked body line too long")),
+        }
+    }
+
+    /// Decodes as far as the bytes so far allow, handing out at most
+    /// `max_data` bytes of body at a time. `None` means more raw bytes are
+    /// needed; `End` keeps being returned once the body is over.
+    pub fn next_event(&mut self, max_data: usize) -> io::Result<Option<BodyEvent>> {
+        loop {
+            match self.state {
+                DecodeState::Done => return Ok(Some(BodyEvent::End)),
+                DecodeState::Length(remaining) | DecodeState::ChunkData(remaining) => {
+                    if self.raw.is_empty() {
+                        return Ok(None);
+                    }
+                    let n = remaining.min(self.raw.len() as u64).min(max_data as u64) as usize;
+                    let data = self.raw.split_to(n).freeze();
+                    let remaining = remaining - n as u64;
+                    self.state = match self.state {
+                        DecodeState::Length(_) if remaining == 0 => DecodeState::Done,
+                        DecodeState::Length(_) => DecodeState::Length(remaining),
+                        _ if remaining == 0 => DecodeState::ChunkDataEnd,
+                        _ => DecodeState::ChunkData(remaining),
+                    };
+                    return Ok(Some(BodyEvent::Data(data)));
+                },
+                DecodeState::ChunkSize => {
+                    let Some(size_end) = self.line_end(MAX_CHUNK_LINE)? else {
+                        return Ok(None);
+                    };
+                    let chunk_size = parse_chunk_size(&self.raw[..size_end])?;
+                    self.raw.advance(size_end + 2);
+                    self.state = if chunk_size == 0 {
+                        DecodeState::Trailers
+                    } else {
+                        DecodeState::ChunkData(chunk_size)
+                    };
+                },
+                DecodeState::ChunkDataEnd => {
+                    if self.raw.len() < 2 {
+                        return Ok(None);
+                    }
+                    if !self.raw.starts_with(b"\r\n") {
+                        return Err(invalid_data("chunk data not followed by CRLF"));
+                    }
+                    self.raw.advance(2);
+                    self.state = DecodeState::ChunkSize;
+                },
+                DecodeState::Trailers => {
+                    let budget = MAX_TRAILER_SIZE.saturating_sub(self.trailer_size);
+                    let Some(line_end) = self.line_end(budget)? else {
+                        return Ok(None);
+                    };
+                    // the CRLF counts too, or the total could creep past the cap
+                    if line_end + 2 > budget {
+                        return Err(invalid_data("trailers too large"));
+                    }
+                    self.trailer_size += line_end + 2;
+                    let line = self.raw.split_to(line_end + 2).freeze();
+                    if line_end == 0 {
+                        self.state = DecodeState::Done;
+                        return Ok(Some(BodyEvent::Trailers(std::mem::take(&mut self.trailers))));
+                    }
+                    let (field, _) = Headers::parse_headers(&line).map_err(|e| invalid_data(&e.to_string()))?;
+                    // fields that would change how the message is framed or routed are dropped
+                    if let Some(field) = field
+                        && trailers::is_allowed_trailer(field.name()) {
+                        self.trailers.append_raw(field);
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Pulls a request body off the connection as the handler asks for it,
+/// undoing chunked framing along the way. The decoder starts out holding
+/// whatever was read past the end of the request head.
+pub struct BodyReader<R> {
+    conn: R,
+    decoder: BodyDecoder,
+    trailer_slot: Option<TrailerSlot>,
+}
+
+impl<R: AsyncRead + Unpin> BodyReader<R> {
+    pub fn new(conn: R, leftover: Vec<u8>, framing: Framing) -> Self {
+        let mut decoder = BodyDecoder::new(framing);
+        decoder.extend(&leftover);
+        Self { conn, decoder, trailer_slot: None }
+    }
+
+    /// Hands the trailer fields of a chunked body to `slot` when they arrive.
+    pub fn with_trailer_slot(mut self, slot: TrailerSlot) -> Self {
+        self.trailer_slot = Some(slot);
+        self
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// chunk-size [ BWS ";" chunk-ext ], the extensions being ignored
+fn parse_chunk_size(line: &[u8]) -> io::Result<u64> {
+    let size_end = line.iter().position(|b| *b == b';').unwrap_or(line.len());
+    let size = line[..size_end].trim_ascii_end();
+    if size.is_empty() || size.len() > MAX_CHUNK_SIZE_DIGITS || !size.iter().all(u8::is_ascii_hexdigit) {
+        return Err(invalid_data(&format!("invalid chunk size '{}'", String::from_utf8_lossy(size))));
+    }
+    // all hex digits and at most 16 of them, so this can't fail or overflow
+    Ok(u64::from_str_radix(std::str::from_utf8(size).unwrap_or_default(), 16).unwrap_or_default())
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for BodyReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            match self.decoder.next_event(buf.remaining())? {
+                Some(BodyEvent::Data(data)) => {
+                    buf.put_slice(&data);
+                    return Poll::Ready(Ok(()));
+                },
+                Some(BodyEvent::Trailers(trailers)) => {
+                    if let Some(slot) = &self.trailer_slot {
+                        *slot.lock().unwrap_or_else(|e| e.into_inner()) = Some(trailers);
+                    }
+                    continue;
+                },
+                Some(BodyEvent::End) => return Poll::Ready(Ok(())),
+                None => {},
+            }
+
+            let mut read_buffer = [0u8; READ_BUFFER_SIZE];
+            let mut read_buf = ReadBuf::new(&mut read_buffer);
+            ready!(Pin::new(&mut self.conn).poll_read(cx, &mut read_buf))?;
+            if read_buf.filled().is_empty() {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the body was complete")));
+            }
+            self.decoder.extend(read_buf.filled());
+        }
+    }
+}
+
+/// A request body that hasn't been read yet. Implements `AsyncRead`; use
+/// `chunk` to pull it a piece at a time or `read_to_end` to buffer it.
+pub struct RequestBody(Box<dyn AsyncRead + Send + Sync + Unpin>);
+
+impl RequestBody {
+    pub fn new<R: AsyncRead + Send + Sync + Unpin + 'static>(reader: R) -> Self {
+        Self(Box::new(reader))
+    }
+
+    /// Returns the next piece of the body, or `None` once it's exhausted.
+    pub async fn chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        let n = self.0.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buffer.truncate(n);
+        Ok(Some(buffer))
+    }
+}
+
+impl AsyncRead for RequestBody {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl fmt::Debug for RequestBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RequestBody")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // hands out its data a few bytes at a time to exercise every split point
+    struct Trickle(Vec<u8>, usize);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            let n = self.1.min(self.0.len()).min(buf.remaining());
+            buf.put_slice(&self.0[..n]);
+            self.0.drain(..n);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_across_reads() {
+        let chunked = b"5\r\nHello\r\n6\r\n World\r\n0\r\n\r\n".to_vec();
+        for step in 1..chunked.len() {
+            let (leftover, rest) = chunked.split_at(step.min(3));
+            let mut reader = BodyReader::new(Trickle(rest.to_vec(), step), leftover.to_vec(), Framing::Chunked);
+            let mut body = Vec::new();
+            reader.read_to_end(&mut body).await.unwrap();
+            assert_eq!(body, b"Hello World", "read size {}", step);
+        }
+
+        let chunked = b"5;name=val\r\nHello\r\n6 ; a=\"b\"\r\n World\r\n0\r\nX-Checksum: abc\r\nContent-Length: 5\r\n\r\n".to_vec();
+        for step in 1..chunked.len() {
+            let slot = TrailerSlot::default();
+            let mut reader = BodyReader::new(Trickle(chunked.clone(), step), Vec::new(), Framing::Chunked)
+                .with_trailer_slot(slot.clone());
+            let mut body = Vec::new();
+            reader.read_to_end(&mut body).await.unwrap();
+            assert_eq!(body, b"Hello World", "read size {}", step);
+            let trailers = slot.lock().unwrap().take().unwrap();
+            assert_eq!(trailers.get("x-checksum"), Some("abc"));
+            assert!(!trailers.contains("content-length"));
+        }
+
+        let mut reader = BodyReader::new(Trickle(b"lo World".to_vec(), 2), b"Hel".to_vec(), Framing::Length(8));
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"Hello Wo");
+
+        let mut reader = BodyReader::new(Trickle(b"short".to_vec(), 2), Vec::new(), Framing::Length(8));
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_chunks() {
+        let cases: [&[u8]; 5] = [
+            b"fffffffffffffffff\r\n",
+            b"-5\r\nhello\r\n0\r\n\r\n",
+            b";ext\r\n",
+            b"5\r\nhelloXX0\r\n\r\n",
+            b"0\r\nNo colon here\r\n\r\n",
+        ];
+        for case in cases {
+            let mut reader = BodyReader::new(Trickle(case.to_vec(), 4), Vec::new(), Framing::Chunked);
+            let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", String::from_utf8_lossy(case));
+        }
+
+        // a size line that never ends
+        let endless = vec![b'1'; MAX_CHUNK_LINE + 10];
+        let mut reader = BodyReader::new(Trickle(endless, 64), Vec::new(), Framing::Chunked);
+        assert!(reader.read_to_end(&mut Vec::new()).await.is_err());
+    }
+
+    #[test]
+    fn trailers_stop_at_the_cap() {
+        let decode = |line_len: usize, rest: &[u8]| {
+            let mut raw = b"0\r\nX:".to_vec();
+            raw.extend(std::iter::repeat_n(b'a', line_len - 2));
+            raw.extend(b"\r\n");
+            raw.extend(rest);
+            let mut decoder = BodyDecoder::new(Framing::Chunked);
+            decoder.extend(&raw);
+            loop {
+                match decoder.next_event(usize::MAX) {
+                    Ok(Some(BodyEvent::Trailers(trailers))) => break Ok(trailers),
+                    Ok(Some(_)) => {},
+                    Ok(None) => panic!("waiting for more after a complete body"),
+                    Err(e) => break Err(e),
+                }
+            }
+        };
+        // the whole section, blank line and every CRLF included, can just fill the budget
+        assert!(decode(MAX_TRAILER_SIZE - 4, b"\r\n").unwrap().get("x").is_some());
+        // a line that fills it leaves no room for anything after, not even the blank line
+        assert!(decode(MAX_TRAILER_SIZE - 2, b"\r\n").is_err());
+        assert!(decode(MAX_TRAILER_SIZE - 2, b"Y: b\r\n\r\n").is_err());
+        assert!(decode(MAX_TRAILER_SIZE, b"Y: b\r\n\r\n").is_err());
+    }
+}
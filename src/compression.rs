@@ -0,0 +1,511 @@
+use core::fmt;
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use flate2::Compression;
+use flate2::write::{GzEncoder, MultiGzDecoder, ZlibDecoder, ZlibEncoder};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::typed_headers::parse_quality_list;
+
+// Below this size the framing overhead of the coding eats most of the savings.
+pub const MIN_COMPRESS_SIZE: usize = 1024;
+
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_LG_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+// how much encoded input a streaming decoder is handed at a time
+const DECODE_INPUT_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentCoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate,
+    Identity,
+}
+
+impl ContentCoding {
+    // listed in order of preference when the client weighs several equally
+    const PREFERENCE: [ContentCoding; 4] = [ContentCoding::Brotli, ContentCoding::Zstd, ContentCoding::Gzip, ContentCoding::Deflate];
+
+    pub fn token(&self) -> &'static str {
+        match self {
+            ContentCoding::Brotli => "br",
+            ContentCoding::Zstd => "zstd",
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Deflate => "deflate",
+            ContentCoding::Identity => "identity",
+        }
+    }
+
+    pub fn from_token(token: &str) -> Option<Self> {
+        match token.trim().to_lowercase().as_str() {
+            "br" => Some(ContentCoding::Brotli),
+            "zstd" => Some(ContentCoding::Zstd),
+            "gzip" | "x-gzip" => Some(ContentCoding::Gzip),
+            "deflate" => Some(ContentCoding::Deflate),
+            "identity" => Some(ContentCoding::Identity),
+            _ => None,
+        }
+    }
+}
+
+/// Parses `Accept-Encoding` into `(coding, q)` pairs, q in thousandths. `*` is reported with a
+/// coding of `None`; members we can't make sense of are dropped.
+fn parse_accept_encoding(value: &str) -> Vec<(Option<ContentCoding>, u16)> {
+    let mut accepted = Vec::new();
+    for item in parse_quality_list(value) {
+        if item.value == "*" {
+            accepted.push((None, item.quality));
+        } else if let Some(coding) = ContentCoding::from_token(item.value) {
+            accepted.push((Some(coding), item.quality));
+        }
+    }
+    accepted
+}
+
+/// Picks the best coding out of `available` for the given `Accept-Encoding`.
+/// Without the header only `identity` is used, which is what clients that
+/// can't decode anything would expect in practice.
+pub fn negotiate_from(accept_encoding: Option<&str>, available: &[ContentCoding]) -> ContentCoding {
+    let Some(accept_encoding) = accept_encoding else {
+        return ContentCoding::Identity;
+    };
+    let accepted = parse_accept_encoding(accept_encoding);
+    let weight = |coding: ContentCoding| {
+        accepted.iter().find(|(c, _)| *c == Some(coding))
+            .or_else(|| accepted.iter().find(|(c, _)| c.is_none()))
+            .map(|(_, q)| *q)
+            .unwrap_or(0)
+    };
+
+    let mut best = (ContentCoding::Identity, 0);
+    for coding in ContentCoding::PREFERENCE {
+        if !available.contains(&coding) {
+            continue;
+        }
+        let q = weight(coding);
+        if q > best.1 {
+            best = (coding, q);
+        }
+    }
+    best.0
+}
+
+pub fn negotiate(accept_encoding: Option<&str>) -> ContentCoding {
+    negotiate_from(accept_encoding, &ContentCoding::PREFERENCE)
+}
+
+/// Whether a media type is worth compressing. Images, video, archives and the
+/// like are already compressed and only get bigger.
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(essence.as_str(),
+            "application/json"
+            | "application/javascript"
+            | "application/xml"
+            | "application/wasm"
+            | "application/x-www-form-urlencoded"
+            | "image/svg+xml"
+            | "image/x-icon"
+            | "font/ttf"
+            | "font/otf")
+}
+
+enum Inner {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+}
+
+/// Incremental encoder for a single response body. Each call hands back
+/// whatever compressed output is ready so it can go straight out as a chunk.
+pub struct Encoder(Inner);
+
+impl fmt::Debug for Encoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let coding = match self.0 {
+            Inner::Gzip(_) => ContentCoding::Gzip,
+            Inner::Deflate(_) => ContentCoding::Deflate,
+            Inner::Brotli(_) => ContentCoding::Brotli,
+            Inner::Zstd(_) => ContentCoding::Zstd,
+        };
+        f.debug_tuple("Encoder").field(&coding).finish()
+    }
+}
+
+impl Encoder {
+    pub fn new(coding: ContentCoding) -> std::io::Result<Option<Self>> {
+        let inner = match coding {
+            ContentCoding::Gzip => Inner::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            ContentCoding::Deflate => Inner::Deflate(ZlibEncoder::new(Vec::new(), Compression::default())),
+            ContentCoding::Brotli => Inner::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), BROTLI_BUFFER_SIZE, BROTLI_QUALITY, BROTLI_LG_WINDOW))),
+            ContentCoding::Zstd => Inner::Zstd(zstd::Encoder::new(Vec::new(), ZSTD_LEVEL)?),
+            ContentCoding::Identity => return Ok(None),
+        };
+        Ok(Some(Self(inner)))
+    }
+
+    pub fn encode(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let output = match &mut self.0 {
+            Inner::Gzip(e) => { e.write_all(data)?; e.get_mut() },
+            Inner::Deflate(e) => { e.write_all(data)?; e.get_mut() },
+            Inner::Brotli(e) => { e.write_all(data)?; e.get_mut() },
+            Inner::Zstd(e) => { e.write_all(data)?; e.get_mut() },
+        };
+        Ok(std::mem::take(output))
+    }
+
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self.0 {
+            Inner::Gzip(e) => e.finish(),
+            Inner::Deflate(e) => e.finish(),
+            Inner::Brotli(e) => Ok(e.into_inner()),
+            Inner::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+pub fn compress(coding: ContentCoding, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match Encoder::new(coding)? {
+        Some(mut encoder) => {
+            let mut output = encoder.encode(data)?;
+            output.extend(encoder.finish()?);
+            Ok(output)
+        },
+        None => Ok(data.to_vec()),
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Unsupported(String),
+    TooLarge(usize),
+    Corrupt(std::io::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Unsupported(coding) => write!(f, "unsupported content-coding: {}", coding),
+            DecodeError::TooLarge(limit) => write!(f, "decoded body exceeds {} bytes", limit),
+            DecodeError::Corrupt(e) => write!(f, "malformed encoded body: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes a single coding, giving up as soon as the output grows past
+/// `limit` so a tiny upload can't inflate into gigabytes.
+pub fn decompress(coding: ContentCoding, data: &[u8], limit: usize) -> Result<Vec<u8>, DecodeError> {
+    let reader: Box<dyn Read + '_> = match coding {
+        ContentCoding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(data)),
+        ContentCoding::Deflate => Box::new(flate2::read::ZlibDecoder::new(data)),
+        ContentCoding::Brotli => Box::new(brotli::Decompressor::new(data, BROTLI_BUFFER_SIZE)),
+        ContentCoding::Zstd => Box::new(zstd::Decoder::new(data).map_err(DecodeError::Corrupt)?),
+        ContentCoding::Identity => return Ok(data.to_vec()),
+    };
+
+    let mut decoded = Vec::new();
+    reader.take(limit as u64 + 1).read_to_end(&mut decoded).map_err(DecodeError::Corrupt)?;
+    if decoded.len() > limit {
+        return Err(DecodeError::TooLarge(limit));
+    }
+    Ok(decoded)
+}
+
+/// The codings listed in a `Content-Encoding` value, in the order they were
+/// applied. Any we can't undo make the whole body undecodable.
+pub fn parse_content_encoding(content_encoding: &str) -> Result<Vec<ContentCoding>, DecodeError> {
+    content_encoding.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(|token| ContentCoding::from_token(token).ok_or_else(|| DecodeError::Unsupported(token.to_string())))
+        .collect()
+}
+
+/// Undoes every coding listed in a `Content-Encoding` value. Codings are listed
+/// in the order they were applied, so they come off in reverse.
+pub fn decode_content(content_encoding: &str, data: Vec<u8>, limit: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut data = data;
+    for coding in parse_content_encoding(content_encoding)?.into_iter().rev() {
+        data = decompress(coding, &data, limit)?;
+    }
+    Ok(data)
+}
+
+// Carries a DecodeError through io::Error, so whoever reads a decoded body can
+// still tell an oversized one from a corrupt one.
+fn decode_failed(e: DecodeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn corrupt(e: io::Error) -> io::Error {
+    if e.get_ref().is_some_and(|inner| inner.is::<DecodeError>()) {
+        return e;
+    }
+    decode_failed(DecodeError::Corrupt(e))
+}
+
+// Holds what a streaming decoder has put out until it's read, failing the
+// write that would take the total past the limit. However far one piece of
+// input inflates, no more than the limit is ever kept.
+struct Output {
+    data: Vec<u8>,
+    total: usize,
+    limit: usize,
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.total += buf.len();
+        if self.total > self.limit {
+            return Err(decode_failed(DecodeError::TooLarge(self.limit)));
+        }
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Decompressor {
+    Gzip(MultiGzDecoder<Output>),
+    Deflate(ZlibDecoder<Output>),
+    Brotli(Box<brotli::DecompressorWriter<Output>>),
+    Zstd(zstd::stream::write::Decoder<'static, Output>),
+    Identity(Output),
+}
+
+impl Decompressor {
+    fn new(coding: ContentCoding, output: Output) -> io::Result<Self> {
+        Ok(match coding {
+            ContentCoding::Gzip => Decompressor::Gzip(MultiGzDecoder::new(output)),
+            ContentCoding::Deflate => Decompressor::Deflate(ZlibDecoder::new(output)),
+            ContentCoding::Brotli => Decompressor::Brotli(Box::new(brotli::DecompressorWriter::new(output, BROTLI_BUFFER_SIZE))),
+            ContentCoding::Zstd => Decompressor::Zstd(zstd::stream::write::Decoder::new(output)?),
+            ContentCoding::Identity => Decompressor::Identity(output),
+        })
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Decompressor::Gzip(d) => d.write_all(data),
+            Decompressor::Deflate(d) => d.write_all(data),
+            Decompressor::Brotli(d) => d.write_all(data),
+            Decompressor::Zstd(d) => d.write_all(data),
+            Decompressor::Identity(output) => output.write_all(data),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Decompressor::Gzip(d) => d.try_finish(),
+            Decompressor::Deflate(d) => d.try_finish(),
+            Decompressor::Brotli(d) => d.close(),
+            Decompressor::Zstd(d) => d.flush(),
+            Decompressor::Identity(_) => Ok(()),
+        }
+    }
+
+    fn output(&mut self) -> &mut Output {
+        match self {
+            Decompressor::Gzip(d) => d.get_mut(),
+            Decompressor::Deflate(d) => d.get_mut(),
+            Decompressor::Brotli(d) => d.get_mut(),
+            Decompressor::Zstd(d) => d.get_mut(),
+            Decompressor::Identity(output) => output,
+        }
+    }
+}
+
+/// Undoes one coding on a body as it's read, so a compressed upload never has
+/// to be held whole. Neither the encoded input nor the decoded output may go
+/// past `limit` bytes: the read that would fails with `InvalidData`, carrying
+/// a `DecodeError`.
+pub struct DecodingReader<R> {
+    inner: R,
+    coding: ContentCoding,
+    decompressor: Decompressor,
+    // how much of the decompressor's output has been handed out already
+    read: usize,
+    encoded: usize,
+    limit: usize,
+    finished: bool,
+}
+
+impl<R> fmt::Debug for DecodingReader<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecodingReader")
+            .field("coding", &self.coding)
+            .field("encoded", &self.encoded)
+            .field("limit", &self.limit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: AsyncRead + Unpin> DecodingReader<R> {
+    pub fn new(inner: R, coding: ContentCoding, limit: usize) -> io::Result<Self> {
+        let output = Output { data: Vec::new(), total: 0, limit };
+        Ok(Self {
+            inner,
+            coding,
+            decompressor: Decompressor::new(coding, output)?,
+            read: 0,
+            encoded: 0,
+            limit,
+            finished: false,
+        })
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DecodingReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            let output = this.decompressor.output();
+            if this.read < output.data.len() {
+                let n = (output.data.len() - this.read).min(buf.remaining());
+                buf.put_slice(&output.data[this.read..this.read + n]);
+                this.read += n;
+                if this.read == output.data.len() {
+                    output.data.clear();
+                    this.read = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut input = [0u8; DECODE_INPUT_SIZE];
+            let mut input = ReadBuf::new(&mut input);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut input))?;
+            if input.filled().is_empty() {
+                this.decompressor.finish().map_err(corrupt)?;
+                this.finished = true;
+                continue;
+            }
+            this.encoded += input.filled().len();
+            if this.encoded > this.limit {
+                return Poll::Ready(Err(decode_failed(DecodeError::TooLarge(this.limit))));
+            }
+            this.decompressor.write_all(input.filled()).map_err(corrupt)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negotiate_accept_encoding() {
+        assert_eq!(negotiate(None), ContentCoding::Identity);
+        assert_eq!(negotiate(Some("")), ContentCoding::Identity);
+        assert_eq!(negotiate(Some("gzip")), ContentCoding::Gzip);
+        assert_eq!(negotiate(Some("gzip, deflate, br, zstd")), ContentCoding::Brotli);
+        assert_eq!(negotiate(Some("gzip;q=1.0, br;q=0.5")), ContentCoding::Gzip);
+        assert_eq!(negotiate(Some("br;q=0, *")), ContentCoding::Zstd);
+        assert_eq!(negotiate(Some("*;q=0")), ContentCoding::Identity);
+        assert_eq!(negotiate(Some("identity")), ContentCoding::Identity);
+        assert_eq!(negotiate(Some("compress, X-GZIP")), ContentCoding::Gzip);
+        assert_eq!(negotiate_from(Some("br, gzip;q=0.8"), &[ContentCoding::Gzip]), ContentCoding::Gzip);
+    }
+
+    #[test]
+    fn compressible_types() {
+        assert!(is_compressible("text/html"));
+        assert!(is_compressible("application/json; charset=utf-8"));
+        assert!(is_compressible("application/problem+json"));
+        assert!(!is_compressible("video/mp4"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/gzip"));
+    }
+
+    #[test]
+    fn streaming_round_trip() {
+        let body = "{\"hello\": \"world\"}\n".repeat(200);
+        for coding in ContentCoding::PREFERENCE {
+            let mut encoder = Encoder::new(coding).unwrap().unwrap();
+            let mut encoded = Vec::new();
+            for chunk in body.as_bytes().chunks(100) {
+                encoded.extend(encoder.encode(chunk).unwrap());
+            }
+            encoded.extend(encoder.finish().unwrap());
+            assert!(encoded.len() < body.len(), "{:?} didn't shrink the body", coding);
+
+            let mut decoded = Vec::new();
+            match coding {
+                ContentCoding::Gzip => { flate2::read::GzDecoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap(); },
+                ContentCoding::Deflate => { flate2::read::ZlibDecoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap(); },
+                ContentCoding::Brotli => { brotli::Decompressor::new(&encoded[..], 4096).read_to_end(&mut decoded).unwrap(); },
+                ContentCoding::Zstd => { decoded = zstd::decode_all(&encoded[..]).unwrap(); },
+                ContentCoding::Identity => unreachable!(),
+            }
+            assert_eq!(decoded, body.as_bytes(), "{:?} round trip", coding);
+        }
+    }
+
+    #[tokio::test]
+    async fn decode_as_a_stream() {
+        use tokio::io::AsyncReadExt;
+
+        let body = b"telemetry ".repeat(2000);
+        for coding in ContentCoding::PREFERENCE {
+            let encoded = compress(coding, &body).unwrap();
+            let mut decoded = Vec::new();
+            DecodingReader::new(&encoded[..], coding, body.len()).unwrap().read_to_end(&mut decoded).await.unwrap();
+            assert_eq!(decoded, body, "{:?}", coding);
+
+            let mut decoded = Vec::new();
+            let e = DecodingReader::new(&encoded[..], coding, body.len() - 1).unwrap().read_to_end(&mut decoded).await.unwrap_err();
+            assert!(matches!(e.get_ref().and_then(|e| e.downcast_ref()), Some(DecodeError::TooLarge(_))), "{:?}: {}", coding, e);
+        }
+
+        // a bomb stops at the limit instead of being inflated whole
+        let bomb = compress(ContentCoding::Gzip, &vec![0u8; 16 << 20]).unwrap();
+        let mut reader = DecodingReader::new(&bomb[..], ContentCoding::Gzip, 1 << 20).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).await.is_err());
+        assert!(reader.decompressor.output().data.len() <= 1 << 20);
+
+        // and so does encoded input, even when it decodes to next to nothing
+        let padded = compress(ContentCoding::Gzip, b"").unwrap().repeat(100);
+        let mut reader = DecodingReader::new(&padded[..], ContentCoding::Gzip, padded.len() - 1).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).await.is_err());
+
+        let e = DecodingReader::new(&body[..], ContentCoding::Gzip, 1 << 20).unwrap().read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert!(matches!(e.get_ref().and_then(|e| e.downcast_ref()), Some(DecodeError::Corrupt(_))), "{}", e);
+    }
+
+    #[test]
+    fn decode_with_limit() {
+        let body = b"telemetry ".repeat(100);
+        for coding in ContentCoding::PREFERENCE {
+            let encoded = compress(coding, &body).unwrap();
+            assert_eq!(decompress(coding, &encoded, body.len()).unwrap(), body);
+            assert!(matches!(decompress(coding, &encoded, body.len() - 1), Err(DecodeError::TooLarge(_))));
+        }
+
+        let layered = compress(ContentCoding::Brotli, &compress(ContentCoding::Gzip, &body).unwrap()).unwrap();
+        assert_eq!(decode_content("gzip, br", layered, 4096).unwrap(), body);
+
+        // 8 MiB of zeros squeezes into a few KiB
+        let bomb = compress(ContentCoding::Gzip, &vec![0u8; 8 << 20]).unwrap();
+        assert!(matches!(decode_content("gzip", bomb, 1 << 20), Err(DecodeError::TooLarge(_))));
+
+        assert!(matches!(decode_content("compress", body.clone(), 4096), Err(DecodeError::Unsupported(_))));
+        assert!(matches!(decode_content("gzip", body, 4096), Err(DecodeError::Corrupt(_))));
+    }
+}
@@ -0,0 +1,201 @@
+use core::fmt;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+
+use crate::headers::Headers;
+use crate::range::truncate_to_seconds;
+use crate::request::HttpMethod;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityTag {
+    pub weak: bool,
+    pub tag: String,
+}
+
+impl EntityTag {
+    pub fn strong(tag: &str) -> Self {
+        Self { weak: false, tag: tag.to_string() }
+    }
+
+    pub fn weak(tag: &str) -> Self {
+        Self { weak: true, tag: tag.to_string() }
+    }
+
+    /// Validator for a file on disk. Modification times only have one second of
+    /// resolution once they go through `Last-Modified`, so a file touched within
+    /// the last second might still change without its tag changing; such tags
+    /// are marked weak.
+    pub fn from_metadata(len: u64, modified: SystemTime) -> Self {
+        let secs = modified.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        let tag = format!("{:x}-{:x}", secs, len);
+        let settled = SystemTime::now().duration_since(modified).is_ok_and(|age| age >= Duration::from_secs(1));
+        if settled { Self::strong(&tag) } else { Self::weak(&tag) }
+    }
+
+    pub fn from_body(body: &[u8]) -> Self {
+        let digest = Sha256::digest(body);
+        Self::strong(&format!("{:x}", digest)[..32])
+    }
+
+    pub fn parse(value: &str) -> Result<Self> {
+        let value = value.trim();
+        let (weak, opaque) = match value.strip_prefix("W/") {
+            Some(rest) => (true, rest),
+            None => (false, value),
+        };
+        let Some(tag) = opaque.strip_prefix('"').and_then(|s| s.strip_suffix('"')) else {
+            bail!("entity-tag must be quoted: {}", value);
+        };
+        // etagc = %x21 / %x23-7E / obs-text
+        if tag.bytes().any(|b| b == b'"' || b < 0x21 || b == 0x7f) {
+            bail!("invalid character in entity-tag: {}", value);
+        }
+        Ok(Self { weak, tag: tag.to_string() })
+    }
+
+    pub fn strong_eq(&self, other: &EntityTag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    pub fn weak_eq(&self, other: &EntityTag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for EntityTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.weak {
+            write!(f, "W/\"{}\"", self.tag)
+        } else {
+            write!(f, "\"{}\"", self.tag)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    Proceed,
+    NotModified,
+    Failed,
+}
+
+// `*` or a comma separated list of entity-tags. Members that don't parse are
+// skipped rather than failing the whole header.
+fn matches_any(value: &str, current: Option<&EntityTag>, compare: fn(&EntityTag, &EntityTag) -> bool) -> bool {
+    let Some(current) = current else {
+        return false;
+    };
+    if value.trim() == "*" {
+        return true;
+    }
+    value.split(',')
+        .filter_map(|candidate| EntityTag::parse(candidate).ok())
+        .any(|candidate| compare(&candidate, current))
+}
+
+fn parse_date(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.trim()).ok()
+}
+
+/// Whether the request is made conditional on the state of its target.
+pub fn has_preconditions(headers: &Headers) -> bool {
+    ["if-match", "if-none-match", "if-modified-since", "if-unmodified-since"].iter().any(|name| headers.contains(name))
+}
+
+/// Evaluates the request preconditions in the order given by RFC 9110 §13.2.2.
+/// `If-Range` is left to the range logic since it never fails a request.
+pub fn evaluate(method: HttpMethod, headers: &Headers, etag: Option<&EntityTag>, last_modified: Option<SystemTime>) -> Precondition {
+    let last_modified = last_modified.map(truncate_to_seconds);
+    let safe = method.is_safe();
+
+    if let Some(if_match) = headers.get_combined("if-match") {
+        if !matches_any(&if_match, etag, EntityTag::strong_eq) {
+            return Precondition::Failed;
+        }
+    } else if let Some(since) = headers.get("if-unmodified-since").and_then(parse_date)
+        && let Some(last_modified) = last_modified
+        && last_modified > since {
+        return Precondition::Failed;
+    }
+
+    if let Some(if_none_match) = headers.get_combined("if-none-match") {
+        if matches_any(&if_none_match, etag, EntityTag::weak_eq) {
+            return if safe { Precondition::NotModified } else { Precondition::Failed };
+        }
+    } else if safe
+        && let Some(since) = headers.get("if-modified-since").and_then(parse_date)
+        && let Some(last_modified) = last_modified
+        && last_modified <= since {
+        return Precondition::NotModified;
+    }
+
+    Precondition::Proceed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::new();
+        for (k, v) in pairs {
+            headers.insert(k, v);
+        }
+        headers
+    }
+
+    #[test]
+    fn entity_tag_parsing() {
+        assert_eq!(EntityTag::parse("\"xyzzy\"").unwrap(), EntityTag::strong("xyzzy"));
+        assert_eq!(EntityTag::parse(" W/\"xyzzy\" ").unwrap(), EntityTag::weak("xyzzy"));
+        assert_eq!(EntityTag::parse("\"\"").unwrap(), EntityTag::strong(""));
+        assert!(EntityTag::parse("xyzzy").is_err());
+        assert!(EntityTag::parse("w/\"xyzzy\"").is_err());
+        assert!(EntityTag::parse("\"xy zzy\"").is_err());
+        assert_eq!(EntityTag::weak("1").to_string(), "W/\"1\"");
+    }
+
+    #[test]
+    fn entity_tag_comparison() {
+        let cases = [
+            (EntityTag::weak("1"), EntityTag::weak("1"), false, true),
+            (EntityTag::weak("1"), EntityTag::weak("2"), false, false),
+            (EntityTag::weak("1"), EntityTag::strong("1"), false, true),
+            (EntityTag::strong("1"), EntityTag::strong("1"), true, true),
+        ];
+        for (a, b, strong, weak) in cases {
+            assert_eq!(a.strong_eq(&b), strong, "{} vs {}", a, b);
+            assert_eq!(a.weak_eq(&b), weak, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn precondition_precedence() {
+        let etag = EntityTag::strong("abc");
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let before = httpdate::fmt_http_date(modified - Duration::from_secs(60));
+        let after = httpdate::fmt_http_date(modified + Duration::from_secs(60));
+        let run = |method, pairs: &[(&str, &str)]| evaluate(method, &headers(pairs), Some(&etag), Some(modified));
+
+        assert_eq!(run(HttpMethod::Get, &[]), Precondition::Proceed);
+        assert_eq!(run(HttpMethod::Get, &[("if-none-match", "\"abc\"")]), Precondition::NotModified);
+        assert_eq!(run(HttpMethod::Get, &[("if-none-match", "W/\"abc\"")]), Precondition::NotModified);
+        assert_eq!(run(HttpMethod::Get, &[("if-none-match", "\"x\", \"y\"")]), Precondition::Proceed);
+        assert_eq!(run(HttpMethod::Post, &[("if-none-match", "*")]), Precondition::Failed);
+        assert_eq!(run(HttpMethod::Get, &[("if-match", "\"abc\"")]), Precondition::Proceed);
+        assert_eq!(run(HttpMethod::Get, &[("if-match", "W/\"abc\"")]), Precondition::Failed);
+        assert_eq!(run(HttpMethod::Get, &[("if-modified-since", &after)]), Precondition::NotModified);
+        assert_eq!(run(HttpMethod::Get, &[("if-modified-since", &before)]), Precondition::Proceed);
+        assert_eq!(run(HttpMethod::Get, &[("if-unmodified-since", &before)]), Precondition::Failed);
+        assert_eq!(run(HttpMethod::Get, &[("if-unmodified-since", &after)]), Precondition::Proceed);
+
+        // If-None-Match takes precedence over If-Modified-Since
+        assert_eq!(run(HttpMethod::Get, &[("if-none-match", "\"x\""), ("if-modified-since", &after)]), Precondition::Proceed);
+        // If-Match takes precedence over If-Unmodified-Since
+        assert_eq!(run(HttpMethod::Get, &[("if-match", "*"), ("if-unmodified-since", &before)]), Precondition::Proceed);
+        // an invalid date is ignored
+        assert_eq!(run(HttpMethod::Get, &[("if-modified-since", "yesterday")]), Precondition::Proceed);
+    }
+}
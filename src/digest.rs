@@ -0,0 +1,260 @@
+use core::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha2::{Digest, Sha256, Sha512};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::trailers::TrailerHook;
+
+/// Hash algorithms from the RFC 9530 registry that we can produce and check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    pub fn token(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha-256",
+            DigestAlgorithm::Sha512 => "sha-512",
+        }
+    }
+
+    pub fn from_token(token: &str) -> Option<Self> {
+        match token.trim().to_lowercase().as_str() {
+            "sha-256" => Some(DigestAlgorithm::Sha256),
+            "sha-512" => Some(DigestAlgorithm::Sha512),
+            _ => None,
+        }
+    }
+}
+
+/// A running hash in one of the supported algorithms.
+#[derive(Debug, Clone)]
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            DigestAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+
+    pub fn algorithm(&self) -> DigestAlgorithm {
+        match self {
+            Hasher::Sha256(_) => DigestAlgorithm::Sha256,
+            Hasher::Sha512(_) => DigestAlgorithm::Sha512,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DigestError {
+    Malformed(String),
+    Mismatch(DigestAlgorithm),
+}
+
+impl fmt::Display for DigestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DigestError::Malformed(value) => write!(f, "malformed Content-Digest: {}", value),
+            DigestError::Mismatch(algorithm) => write!(f, "body doesn't match its {} Content-Digest", algorithm.token()),
+        }
+    }
+}
+
+impl std::error::Error for DigestError {}
+
+/// Formats a digest as a `Content-Digest` dictionary member, `sha-256=:base64:`.
+pub fn format_digest(algorithm: DigestAlgorithm, digest: &[u8]) -> String {
+    format!("{}=:{}:", algorithm.token(), BASE64.encode(digest))
+}
+
+pub fn content_digest(algorithm: DigestAlgorithm, body: &[u8]) -> String {
+    let mut hasher = Hasher::new(algorithm);
+    hasher.update(body);
+    format_digest(algorithm, &hasher.finalize())
+}
+
+/// Parses a `Content-Digest` dictionary into its members. Algorithms we don't
+/// know are skipped rather than rejected.
+pub fn parse_digests(value: &str) -> Result<Vec<(DigestAlgorithm, Vec<u8>)>, DigestError> {
+    let mut digests = Vec::new();
+    for member in value.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+        let malformed = || DigestError::Malformed(member.to_string());
+        let (key, value) = member.split_once('=').ok_or_else(malformed)?;
+        let encoded = value.trim().strip_prefix(':').and_then(|v| v.strip_suffix(':')).ok_or_else(malformed)?;
+        let digest = BASE64.decode(encoded).map_err(|_| malformed())?;
+        if let Some(algorithm) = DigestAlgorithm::from_token(key) {
+            digests.push((algorithm, digest));
+        }
+    }
+    Ok(digests)
+}
+
+/// Picks the algorithm a `Want-Content-Digest` likes best among the ones we
+/// support. A weight of 0 means "not this one".
+pub fn negotiate_want(want: &str) -> Option<DigestAlgorithm> {
+    want.split(',')
+        .filter_map(|member| {
+            let (key, weight) = member.split_once('=')?;
+            let algorithm = DigestAlgorithm::from_token(key)?;
+            let weight = weight.trim().parse::<u8>().ok().filter(|w| *w > 0 && *w <= 10)?;
+            Some((algorithm, weight))
+        })
+        .max_by_key(|(_, weight)| *weight)
+        .map(|(algorithm, _)| algorithm)
+}
+
+/// Checks `body` against the strongest supported digest in `Content-Digest`.
+pub fn verify(content_digest: &str, body: &[u8]) -> Result<(), DigestError> {
+    match Verifier::new(content_digest)? {
+        Some(mut verifier) => {
+            verifier.hasher.update(body);
+            verifier.check()
+        },
+        None => Ok(()),
+    }
+}
+
+struct Verifier {
+    hasher: Hasher,
+    expected: Vec<u8>,
+}
+
+impl Verifier {
+    // None when the header only names algorithms we can't check
+    fn new(content_digest: &str) -> Result<Option<Self>, DigestError> {
+        let digests = parse_digests(content_digest)?;
+        let strongest = digests.iter().find(|(a, _)| *a == DigestAlgorithm::Sha512)
+            .or_else(|| digests.first());
+        Ok(strongest.map(|(algorithm, expected)| Verifier { hasher: Hasher::new(*algorithm), expected: expected.clone() }))
+    }
+
+    fn check(self) -> Result<(), DigestError> {
+        let algorithm = self.hasher.algorithm();
+        if self.hasher.finalize() != self.expected {
+            return Err(DigestError::Mismatch(algorithm));
+        }
+        Ok(())
+    }
+}
+
+/// Passes a request body through while hashing it, failing the read that
+/// reaches the end if the body doesn't match its `Content-Digest`.
+pub struct VerifyingReader<R> {
+    inner: R,
+    verifier: Option<Verifier>,
+}
+
+impl<R: AsyncRead + Unpin> VerifyingReader<R> {
+    pub fn new(inner: R, content_digest: &str) -> Result<Self, DigestError> {
+        Ok(VerifyingReader { inner, verifier: Verifier::new(content_digest)? })
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for VerifyingReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if !read.is_empty() {
+            if let Some(verifier) = self.verifier.as_mut() {
+                verifier.hasher.update(read);
+            }
+        } else if buf.remaining() > 0
+            && let Some(verifier) = self.verifier.take() {
+            verifier.check().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Sends `Content-Digest` as a trailer, for bodies whose content isn't known
+/// until it's all been written.
+#[derive(Debug, Clone)]
+pub struct ContentDigestTrailer {
+    hasher: Option<Hasher>,
+}
+
+impl ContentDigestTrailer {
+    pub fn new(algorithm: DigestAlgorithm) -> Self {
+        ContentDigestTrailer { hasher: Some(Hasher::new(algorithm)) }
+    }
+}
+
+impl TrailerHook for ContentDigestTrailer {
+    fn name(&self) -> &str {
+        "Content-Digest"
+    }
+
+    fn update(&mut self, content: &[u8]) {
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(content);
+        }
+    }
+
+    fn value(&mut self) -> String {
+        let hasher = self.hasher.take().unwrap_or(Hasher::new(DigestAlgorithm::Sha256));
+        let algorithm = hasher.algorithm();
+        format_digest(algorithm, &hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    // RFC 9530 appendix D: {"hello": "world"}
+    const HELLO_WORLD: &[u8] = b"{\"hello\": \"world\"}";
+    const HELLO_WORLD_SHA256: &str = "sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:";
+
+    #[test]
+    fn digests_and_wants() {
+        assert_eq!(content_digest(DigestAlgorithm::Sha256, HELLO_WORLD), HELLO_WORLD_SHA256);
+        assert!(verify(HELLO_WORLD_SHA256, HELLO_WORLD).is_ok());
+        assert_eq!(verify(HELLO_WORLD_SHA256, b"{}"), Err(DigestError::Mismatch(DigestAlgorithm::Sha256)));
+        assert!(verify("md5=:AAAA:", b"anything").is_ok());
+        assert!(matches!(verify("sha-256=abc", b""), Err(DigestError::Malformed(_))));
+
+        assert_eq!(negotiate_want("sha-256=1, sha-512=3"), Some(DigestAlgorithm::Sha512));
+        assert_eq!(negotiate_want("sha-512=0, sha-256=2"), Some(DigestAlgorithm::Sha256));
+        assert_eq!(negotiate_want("md5=10"), None);
+    }
+
+    #[tokio::test]
+    async fn verifies_while_streaming() {
+        let mut reader = VerifyingReader::new(HELLO_WORLD, HELLO_WORLD_SHA256).unwrap();
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, HELLO_WORLD);
+
+        let mut reader = VerifyingReader::new(&b"{\"hello\": \"there\"}"[..], HELLO_WORLD_SHA256).unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,345 @@
+use core::fmt;
+use std::future::Future;
+
+use serde::de::value::{Error as ValueError, MapDeserializer, SeqDeserializer};
+use serde::Serialize;
+use serde::de::{self, DeserializeOwned, Deserializer, IntoDeserializer, Visitor};
+
+use crate::handlers::HandlerError;
+use crate::request::HttpRequest;
+use crate::response::{HttpResponse, HttpStatus, IntoResponse};
+use crate::typed_headers::ContentType;
+
+/// Something a handler can pull out of a request, failing with the response
+/// the client should get if the request doesn't have it.
+pub trait FromRequest: Sized {
+    fn from_request(req: &mut HttpRequest) -> impl Future<Output = Result<Self, HandlerError>> + Send;
+}
+
+fn rejection(status_code: HttpStatus, message: impl fmt::Display) -> HandlerError {
+    HandlerError { status_code, message: message.to_string() }
+}
+
+fn require_content_type(req: &HttpRequest, expected: &str) -> Result<(), HandlerError> {
+    let content_type = req.headers.typed::<ContentType>();
+    let matches = match content_type.as_ref().map(|ct| ct.media_type()) {
+        Some(essence) if expected == "application/json" => essence == expected || essence.ends_with("+json"),
+        Some(essence) => essence == expected,
+        None => false,
+    };
+    if !matches {
+        return Err(rejection(HttpStatus::UnsupportedMediaType, format!("expected a Content-Type of {}", expected)));
+    }
+    Ok(())
+}
+
+async fn read_body(req: &mut HttpRequest) -> Result<&[u8], HandlerError> {
+    let limit = req.body_limit;
+    req.read_body_up_to(limit).await
+        .map_err(HandlerError::from_body_error)?
+        .ok_or_else(|| rejection(HttpStatus::ContentTooLarge, format!("body exceeds {} bytes", limit)))
+}
+
+/// `application/json` request body.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned + Send> FromRequest for Json<T> {
+    async fn from_request(req: &mut HttpRequest) -> Result<Self, HandlerError> {
+        require_content_type(req, "application/json")?;
+        let body = read_body(req).await?;
+        serde_json::from_slice(body).map(Json).map_err(|e| {
+            // well-formed JSON of the wrong shape is the client's data at fault, not its syntax
+            let status_code = match e.classify() {
+                serde_json::error::Category::Data => HttpStatus::UnprocessableContent,
+                _ => HttpStatus::BadRequest,
+            };
+            rejection(status_code, e)
+        })
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> HttpResponse {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => HttpResponse::new()
+                .with_body_bytes(body)
+                .with_default_headers()
+                .with_header("Content-Type", "application/json"),
+            Err(e) => rejection(HttpStatus::InternalServerError, e).into_response(),
+        }
+    }
+}
+
+/// `application/x-www-form-urlencoded` request body.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Form<T>(pub T);
+
+impl<T: DeserializeOwned + Send> FromRequest for Form<T> {
+    async fn from_request(req: &mut HttpRequest) -> Result<Self, HandlerError> {
+        require_content_type(req, "application/x-www-form-urlencoded")?;
+        let body = read_body(req).await?;
+        serde_urlencoded::from_bytes(body)
+            .map(Form)
+            .map_err(|e| rejection(HttpStatus::UnprocessableContent, e))
+    }
+}
+
+/// The query string, decoded as `application/x-www-form-urlencoded`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned + Send> FromRequest for Query<T> {
+    async fn from_request(req: &mut HttpRequest) -> Result<Self, HandlerError> {
+        let query = req.request_line.as_ref().and_then(|rl| rl.query()).unwrap_or_default();
+        serde_urlencoded::from_str(query)
+            .map(Query)
+            .map_err(|e| rejection(HttpStatus::BadRequest, e))
+    }
+}
+
+/// Parameters captured by `HttpRequest::match_route`. `T` can be a struct
+/// keyed by parameter name, a tuple taken in order, or a single value when
+/// the route has one parameter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned + Send> FromRequest for Path<T> {
+    async fn from_request(req: &mut HttpRequest) -> Result<Self, HandlerError> {
+        T::deserialize(ParamsDeserializer(&req.params))
+            .map(Path)
+            .map_err(|e| rejection(HttpStatus::BadRequest, format!("invalid path parameter: {}", e)))
+    }
+}
+
+struct ParamsDeserializer<'a>(&'a [(String, String)]);
+
+impl<'a> ParamsDeserializer<'a> {
+    fn single(&self) -> Result<ParamDeserializer<'a>, ValueError> {
+        match self.0 {
+            [(_, value)] => Ok(ParamDeserializer(value)),
+            params => Err(de::Error::custom(format!("expected 1 path parameter, found {}", params.len()))),
+        }
+    }
+}
+
+macro_rules! forward_to_single {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.single()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> Deserializer<'de> for ParamsDeserializer<'a> {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let entries = self.0.iter().map(|(name, value)| (name.as_str(), ParamDeserializer(value)));
+        visitor.visit_map(MapDeserializer::new(entries))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_seq(SeqDeserializer::new(self.0.iter().map(|(_, value)| ParamDeserializer(value))))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        self.single()?.deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+
+    forward_to_single! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_f32 deserialize_f64
+        deserialize_char deserialize_str deserialize_string deserialize_bytes deserialize_byte_buf
+        deserialize_option deserialize_unit deserialize_identifier deserialize_ignored_any
+    }
+}
+
+/// A single captured value, parsed into whatever primitive the target asks for.
+struct ParamDeserializer<'a>(&'a str);
+
+macro_rules! parse_value {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                let value = self.0.parse().map_err(|_| de::Error::custom(format!("cannot parse '{}'", self.0)))?;
+                visitor.$visit(value)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> Deserializer<'de> for ParamDeserializer<'a> {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_str(self.0)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    parse_value! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    serde::forward_to_deserialize_any! {
+        str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de, 'a> IntoDeserializer<'de, ValueError> for ParamDeserializer<'a> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::body::RequestBody;
+    use crate::request::{HttpMethod, HttpVersion, RequestLine};
+    use serde::Deserialize;
+
+    fn request(method: HttpMethod, target: &str) -> HttpRequest {
+        HttpRequest::new().with_request_line(RequestLine {
+            method,
+            target: target.to_string(),
+            version: HttpVersion::HTTP11,
+        })
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Item {
+        id: u32,
+        name: String,
+        tag: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Kind {
+        Photo,
+        Video,
+    }
+
+    #[tokio::test]
+    async fn path_params() {
+        let mut req = request(HttpMethod::Get, "/items/42/widget%20x?tag=ignored");
+        assert!(!req.match_route("/items/{id}"));
+        assert!(req.match_route("/items/{id}/{name}"));
+        assert_eq!(req.params, [("id".to_string(), "42".to_string()), ("name".to_string(), "widget x".to_string())]);
+
+        let Path(item) = Path::<Item>::from_request(&mut req).await.unwrap();
+        assert_eq!(item, Item { id: 42, name: "widget x".to_string(), tag: None });
+        let Path((id, name)) = Path::<(u64, String)>::from_request(&mut req).await.unwrap();
+        assert_eq!((id, name.as_str()), (42, "widget x"));
+        assert!(Path::<u32>::from_request(&mut req).await.is_err());
+
+        let mut req = request(HttpMethod::Get, "/media/video");
+        assert!(req.match_route("/media/{kind}"));
+        assert_eq!(Path::<Kind>::from_request(&mut req).await.unwrap().0, Kind::Video);
+
+        let mut req = request(HttpMethod::Get, "/items/x/y");
+        assert!(req.match_route("/items/{id}/{name}"));
+        let err = Path::<Item>::from_request(&mut req).await.unwrap_err();
+        assert_eq!(err.status_code, HttpStatus::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn query_string() {
+        let mut req = request(HttpMethod::Get, "/search?id=7&name=Big+Box&tag=a%26b");
+        let Query(item) = Query::<Item>::from_request(&mut req).await.unwrap();
+        assert_eq!(item, Item { id: 7, name: "Big Box".to_string(), tag: Some("a&b".to_string()) });
+
+        let mut req = request(HttpMethod::Get, "/search?id=seven&name=x");
+        assert_eq!(Query::<Item>::from_request(&mut req).await.unwrap_err().status_code, HttpStatus::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn bodies() {
+        let mut req = request(HttpMethod::Post, "/items")
+            .with_header("content-type", "application/json; charset=utf-8")
+            .with_body(br#"{"id": 1, "name": "cup"}"#.to_vec());
+        assert_eq!(Json::<Item>::from_request(&mut req).await.unwrap().0.name, "cup");
+
+        let cases: [(&str, &[u8], HttpStatus); 4] = [
+            ("text/plain", br#"{"id": 1, "name": "cup"}"#, HttpStatus::UnsupportedMediaType),
+            ("application/json", br#"{"id": 1, "name": "#, HttpStatus::BadRequest),
+            ("application/json", br#"{"id": "one", "name": "cup"}"#, HttpStatus::UnprocessableContent),
+            ("application/x-www-form-urlencoded", b"id=1&name=cup", HttpStatus::UnsupportedMediaType),
+        ];
+        for (content_type, body, status) in cases {
+            let mut req = request(HttpMethod::Post, "/items")
+                .with_header("content-type", content_type)
+                .with_body(body.to_vec());
+            assert_eq!(Json::<Item>::from_request(&mut req).await.unwrap_err().status_code, status, "{}", content_type);
+        }
+
+        let mut req = request(HttpMethod::Post, "/items")
+            .with_header("content-type", "application/x-www-form-urlencoded")
+            .with_body(b"id=3&name=tea+pot".to_vec());
+        assert_eq!(Form::<Item>::from_request(&mut req).await.unwrap().0, Item { id: 3, name: "tea pot".to_string(), tag: None });
+
+        let mut req = request(HttpMethod::Post, "/items")
+            .with_header("content-type", "application/x-www-form-urlencoded")
+            .with_body(b"name=tea+pot".to_vec());
+        assert_eq!(Form::<Item>::from_request(&mut req).await.unwrap_err().status_code, HttpStatus::UnprocessableContent);
+
+        let json = br#"{"id": 1, "name": "cup"}"#;
+        let mut req = request(HttpMethod::Post, "/items").with_header("content-type", "application/json");
+        req.body_limit = json.len() - 1;
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::io::AsyncWriteExt::write_all(&mut client, json).await.unwrap();
+        req = req.with_body_stream(RequestBody::new(server));
+        assert_eq!(Json::<Item>::from_request(&mut req).await.unwrap_err().status_code, HttpStatus::ContentTooLarge);
+    }
+}
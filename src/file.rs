@@ -0,0 +1,187 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::fs::File;
+
+use crate::compression::{self, ContentCoding};
+use crate::conditional::{self, EntityTag, Precondition};
+use crate::handlers::HandlerError;
+use crate::range::{self, ByteRange, RangeOutcome};
+use crate::request::HttpRequest;
+use crate::response::{HttpResponse, HttpStatus, ResponseWriter};
+
+const PRECOMPRESSED_CODINGS: [ContentCoding; 2] = [ContentCoding::Brotli, ContentCoding::Gzip];
+
+/// A file on disk served with support for conditional requests, `Range` and
+/// `If-Range`, and optionally precompressed `.br`/`.gz` siblings.
+#[derive(Debug, Clone)]
+pub struct StaticFile {
+    path: PathBuf,
+    content_type: String,
+    precompressed: bool,
+}
+
+impl StaticFile {
+    pub fn new(path: impl AsRef<Path>, content_type: &str) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            content_type: content_type.to_string(),
+            precompressed: false,
+        }
+    }
+
+    /// Looks for `<path>.br` and `<path>.gz` next to the file and serves one of
+    /// those instead when the client accepts that coding.
+    pub fn with_precompressed(mut self) -> Self {
+        self.precompressed = true;
+        self
+    }
+
+    fn sibling(&self, coding: ContentCoding) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(match coding {
+            ContentCoding::Brotli => ".br",
+            _ => ".gz",
+        });
+        PathBuf::from(path)
+    }
+
+    async fn open(&self, req: &HttpRequest) -> Result<(File, ContentCoding), HandlerError> {
+        if self.precompressed {
+            let mut available = Vec::new();
+            for coding in PRECOMPRESSED_CODINGS {
+                if tokio::fs::try_exists(self.sibling(coding)).await.unwrap_or(false) {
+                    available.push(coding);
+                }
+            }
+            let coding = compression::negotiate_from(req.headers.get_combined("accept-encoding").as_deref(), &available);
+            if coding != ContentCoding::Identity
+                && let Ok(f) = File::open(self.sibling(coding)).await {
+                return Ok((f, coding));
+            }
+        }
+        let f = File::open(&self.path).await?;
+        Ok((f, ContentCoding::Identity))
+    }
+
+    pub async fn serve(&self, writer: &mut ResponseWriter, req: &HttpRequest) -> Result<(), HandlerError> {
+        let (mut f, coding) = self.open(req).await?;
+        let metadata = f.metadata().await?;
+        let complete_length = metadata.len();
+        let modified = metadata.modified().ok();
+        let last_modified = modified.map(range::truncate_to_seconds);
+        let etag = modified.map(|m| EntityTag::from_metadata(complete_length, m));
+
+        let mut response = HttpResponse::new()
+            .with_header("Accept-Ranges", "bytes")
+            .with_header("Connection", "close");
+        if let Some(last_modified) = last_modified {
+            response = response.with_header("Last-Modified", &httpdate::fmt_http_date(last_modified));
+        }
+        if let Some(etag) = &etag {
+            response = response.with_header("ETag", &etag.to_string());
+        }
+        if self.precompressed {
+            response = response.with_header("Vary", "Accept-Encoding");
+        }
+        if coding != ContentCoding::Identity {
+            response = response.with_header("Content-Encoding", coding.token());
+        }
+
+        if let Some(rl) = &req.request_line {
+            match conditional::evaluate(rl.method, &req.headers, etag.as_ref(), last_modified) {
+                Precondition::Proceed => {},
+                Precondition::NotModified => {
+                    write_head(writer, &response.with_status(HttpStatus::NotModified)).await?;
+                    writer.write_body_done().await?;
+                    return Ok(());
+                },
+                Precondition::Failed => {
+                    let response = response
+                        .with_status(HttpStatus::PreconditionFailed)
+                        .with_header("Content-Length", "0");
+                    write_head(writer, &response).await?;
+                    writer.write_body_done().await?;
+                    return Ok(());
+                },
+            }
+        }
+
+        match range::evaluate(req, complete_length, etag.as_ref(), last_modified) {
+            RangeOutcome::Full => {
+                let response = response
+                    .with_status(HttpStatus::Ok)
+                    .with_header("Content-Type", &self.content_type)
+                    .with_header("Content-Length", &complete_length.to_string());
+                write_head(writer, &response).await?;
+                let full = ByteRange { start: 0, end: complete_length.saturating_sub(1) };
+                if complete_length > 0 {
+                    copy_range(writer, &mut f, full).await?;
+                }
+            },
+            RangeOutcome::Partial(ranges) if ranges.len() == 1 => {
+                let range = ranges[0];
+                let response = response
+                    .with_status(HttpStatus::PartialContent)
+                    .with_header("Content-Type", &self.content_type)
+                    .with_header("Content-Range", &range.content_range(complete_length))
+                    .with_header("Content-Length", &range.length().to_string());
+                write_head(writer, &response).await?;
+                copy_range(writer, &mut f, range).await?;
+            },
+            RangeOutcome::Partial(ranges) => {
+                let boundary = multipart_boundary();
+                let part_heads: Vec<String> = ranges.iter()
+                    .map(|range| format!("--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n", boundary, &self.content_type, range.content_range(complete_length)))
+                    .collect();
+                let closing = format!("--{}--\r\n", boundary);
+                // every part is followed by a CRLF before the next delimiter
+                let content_length = part_heads.iter().map(|h| h.len() as u64).sum::<u64>()
+                    + ranges.iter().map(|r| r.length() + 2).sum::<u64>()
+                    + closing.len() as u64;
+
+                let response = response
+                    .with_status(HttpStatus::PartialContent)
+                    .with_header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary))
+                    .with_header("Content-Length", &content_length.to_string());
+                write_head(writer, &response).await?;
+                for (range, head) in ranges.iter().zip(part_heads.iter()) {
+                    writer.write_body(head.as_bytes()).await?;
+                    copy_range(writer, &mut f, *range).await?;
+                    writer.write_body(b"\r\n").await?;
+                }
+                writer.write_body(closing.as_bytes()).await?;
+            },
+            RangeOutcome::NotSatisfiable => {
+                let response = response
+                    .with_status(HttpStatus::RangeNotSatisfiable)
+                    .with_header("Content-Range", &format!("bytes */{}", complete_length))
+                    .with_header("Content-Length", "0");
+                write_head(writer, &response).await?;
+            },
+        }
+
+        writer.write_body_done().await?;
+        Ok(())
+    }
+}
+
+pub async fn serve_file(writer: &mut ResponseWriter, req: &HttpRequest, path: impl AsRef<Path>, content_type: &str) -> Result<(), HandlerError> {
+    StaticFile::new(path, content_type).serve(writer, req).await
+}
+
+async fn write_head(writer: &mut ResponseWriter, response: &HttpResponse) -> Result<(), HandlerError> {
+    writer.write_status(&response.status).await?;
+    writer.write_headers(&response.headers).await?;
+    Ok(())
+}
+
+async fn copy_range(writer: &mut ResponseWriter, f: &mut File, range: ByteRange) -> Result<(), HandlerError> {
+    writer.write_file(f, range.start, range.length()).await?;
+    Ok(())
+}
+
+fn multipart_boundary() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default();
+    format!("{:032x}", nanos)
+}
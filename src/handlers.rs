@@ -1,8 +1,15 @@
-use crate::response::{HttpResponse, ResponseWriter, HttpStatus};
+use crate::compression::DecodeError;
+use crate::digest::DigestError;
+use crate::multipart::{Multipart, MultipartError};
+use crate::response::{Html, HttpResponse, IntoResponse, ResponseWriter, HttpStatus};
 use crate::request::HttpRequest;
+use crate::extract::{FromRequest, Path, Query};
+use crate::file::serve_file;
+use crate::trailers::{LengthTrailer, Sha256Trailer};
+use crate::typed_headers::ContentLength;
 
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
 
 static DEFAULT_BODY: &str = "<html>
@@ -48,133 +55,244 @@ pub struct HandlerError {
 impl HandlerError {
     pub fn to_response(&self) -> HttpResponse {
         HttpResponse::new()
-            .with_status(self.status_code)
+            .with_status(self.status_code.clone())
             .with_body(&self.message)
             .with_default_headers()
     }
 }
 
-//pub type Handler = fn(&mut ResponseWriter, &HttpRequest) -> Pin<Box<dyn Future<Output = Result<(), HandlerError>> + Send>>;
+impl IntoResponse for HandlerError {
+    fn into_response(self) -> HttpResponse {
+        self.to_response()
+    }
+}
 
-pub async fn default_handler(writer: &mut ResponseWriter, _req: &HttpRequest) -> Result<(), HandlerError> {
-    let response = HttpResponse::new()
-        .with_status(HttpStatus::Ok)
-        .with_body(DEFAULT_BODY)
-        .with_default_headers()
-        .with_header("Content-Type", "text/html");
-    writer.write_all(&response).await.map_err(|e| HandlerError { status_code: HttpStatus::InternalServerError, message: e.to_string() })?;
+impl From<std::io::Error> for HandlerError {
+    fn from(e: std::io::Error) -> Self {
+        HandlerError { status_code: HttpStatus::InternalServerError, message: e.to_string() }
+    }
+}
+
+impl HandlerError {
+    /// For a request body that couldn't be read: the client's fault, and a 413
+    /// if it decoded to more than we take.
+    pub fn from_body_error(e: std::io::Error) -> Self {
+        let status_code = match e.get_ref().and_then(|inner| inner.downcast_ref::<DecodeError>()) {
+            Some(DecodeError::TooLarge(_)) => HttpStatus::ContentTooLarge,
+            _ => HttpStatus::BadRequest,
+        };
+        HandlerError { status_code, message: e.to_string() }
+    }
+}
+
+impl From<reqwest::Error> for HandlerError {
+    fn from(e: reqwest::Error) -> Self {
+        HandlerError { status_code: HttpStatus::InternalServerError, message: e.to_string() }
+    }
+}
+
+impl From<DigestError> for HandlerError {
+    fn from(e: DigestError) -> Self {
+        HandlerError { status_code: HttpStatus::BadRequest, message: e.to_string() }
+    }
+}
+
+impl From<DecodeError> for HandlerError {
+    fn from(e: DecodeError) -> Self {
+        let status_code = match e {
+            DecodeError::Unsupported(_) => HttpStatus::UnsupportedMediaType,
+            DecodeError::TooLarge(_) => HttpStatus::ContentTooLarge,
+            DecodeError::Corrupt(_) => HttpStatus::BadRequest,
+        };
+        HandlerError { status_code, message: e.to_string() }
+    }
+}
+
+impl From<MultipartError> for HandlerError {
+    fn from(e: MultipartError) -> Self {
+        let status_code = match e {
+            MultipartError::NotMultipart => HttpStatus::UnsupportedMediaType,
+            MultipartError::PartTooLarge(_) | MultipartError::TotalTooLarge(_) | MultipartError::TooManyParts(_) => HttpStatus::ContentTooLarge,
+            MultipartError::Io(e) => return HandlerError::from_body_error(e),
+            MultipartError::Malformed(_) => HttpStatus::BadRequest,
+        };
+        HandlerError { status_code, message: e.to_string() }
+    }
+}
+
+const MAX_UPLOAD_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Turns away oversized uploads before the client starts sending them.
+pub fn check_expectation(req: &HttpRequest) -> Result<(), HandlerError> {
+    let is_upload = req.request_line.as_ref().is_some_and(|rl| rl.path().to_ascii_lowercase().starts_with("/upload"));
+    let length = req.headers.typed::<ContentLength>();
+    if is_upload && length.is_some_and(|ContentLength(length)| length > MAX_UPLOAD_SIZE) {
+        return Err(HandlerError {
+            status_code: HttpStatus::ContentTooLarge,
+            message: format!("uploads are limited to {} bytes", MAX_UPLOAD_SIZE),
+        });
+    }
     Ok(())
 }
 
-pub async fn video_handler(writer: &mut ResponseWriter, _req: &HttpRequest) -> Result<(), HandlerError> {
-    let mut f = File::open("assets/vim.mp4").await
-        .map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})?;
+//pub type Handler = fn(&mut ResponseWriter, &HttpRequest) -> Pin<Box<dyn Future<Output = Result<(), HandlerError>> + Send>>;
 
-    let final_response = HttpResponse::new()
-        .with_status(HttpStatus::Ok)
-        .with_header("Transfer-Encoding", "chunked")
-        .with_header("Content-Type", "video/mp4")
-        .with_header("Connection", "close");
+pub async fn default_handler(_req: &mut HttpRequest) -> Html<&'static str> {
+    Html(DEFAULT_BODY)
+}
+
+pub async fn video_handler(writer: &mut ResponseWriter, req: &mut HttpRequest) -> Result<(), HandlerError> {
+    serve_file(writer, req, "assets/vim.mp4", "video/mp4").await
+}
 
-    writer.write_status(&final_response.status).await
-        .map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})?;
-    writer.write_headers(&final_response.headers).await
-        .map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})?;
+pub async fn upload_handler(req: &mut HttpRequest) -> Result<String, HandlerError> {
+    let mut hasher = Sha256::new();
+    let mut received = 0usize;
 
-    let mut file_buffer = [0u8; 512];
-    let mut body_copy = Vec::new();
-    while let Ok(n) = f.read(&mut file_buffer).await {
-        if n == 0 {
-            break;
+    // pull the body through a piece at a time rather than holding the whole upload
+    if let Some(mut body) = req.take_body_stream() {
+        while let Some(chunk) = body.chunk().await.map_err(HandlerError::from_body_error)? {
+            received += chunk.len();
+            hasher.update(&chunk);
         }
-        println!("Forwarding video chunk of size {}", &file_buffer[..n].len());
-        body_copy.extend_from_slice(&file_buffer[..n]);
+    } else {
+        received = req.body.len();
+        hasher.update(&req.body);
+    }
+
+    Ok(format!("received {} bytes, sha256 {:x}\n", received, hasher.finalize()))
+}
+
+pub async fn form_handler(req: &mut HttpRequest) -> Result<String, HandlerError> {
+    let mut multipart = Multipart::from_request(req)?;
+    let mut summary = String::new();
 
-        writer.write_chunked_body(&file_buffer[..n]).await
-            .map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})?;
+    while let Some(mut part) = multipart.next_part().await? {
+        let name = part.name.clone().unwrap_or_default();
+        match part.filename.clone() {
+            Some(filename) => {
+                // files are hashed as they stream past rather than collected
+                let mut hasher = Sha256::new();
+                let mut size = 0;
+                while let Some(chunk) = part.chunk().await? {
+                    size += chunk.len();
+                    hasher.update(&chunk);
+                }
+                summary.push_str(&format!("{}: file '{}' ({}, {} bytes, sha256 {:x})\n", name, filename, part.content_type(), size, hasher.finalize()));
+            },
+            None => {
+                let value = part.text().await?;
+                summary.push_str(&format!("{}: {}\n", name, value));
+            },
+        }
     }
-    writer.write_chunked_body_done().await
-        .map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})?;
-    
-    writer.write_trailers(&body_copy).await
-        .map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})?;
-    
-    Ok(())
+
+    Ok(summary)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GreetQuery {
+    greeting: Option<String>,
 }
 
-pub async fn proxy_handler(writer: &mut ResponseWriter, req: &HttpRequest) -> Result<(), HandlerError> {
-    let (_trash, end_point) = req.request_line.as_ref().unwrap().target
-        .split_once("httpbin/")
+pub async fn greet_handler(req: &mut HttpRequest) -> Result<String, HandlerError> {
+    let Path(name) = Path::<String>::from_request(req).await?;
+    let Query(query) = Query::<GreetQuery>::from_request(req).await?;
+
+    Ok(format!("{}, {}!\n", query.greeting.as_deref().unwrap_or("Hello"), name))
+}
+
+pub async fn proxy_handler(writer: &mut ResponseWriter, req: &mut HttpRequest) -> Result<(), HandlerError> {
+    let target = &req.request_line.as_ref().unwrap().target;
+    // the prefix matched regardless of case, but the rest goes upstream as sent
+    let start = target.to_ascii_lowercase().find("httpbin/")
         .ok_or_else(|| HandlerError{ status_code: HttpStatus::InternalServerError, message: "invalid endpoint".to_string() })?;
+    let end_point = &target[start + "httpbin/".len()..];
 
 
     let dest_url = format!("https://httpbin.org/{}", end_point);
     println!("Forwarding request to: {}...", dest_url);
 
-    let mut dest_response = reqwest::get(dest_url).await
-        .map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})?;
+    let mut dest_response = reqwest::get(dest_url).await?;
 
     let final_response = HttpResponse::new()
         .with_status(HttpStatus::Ok)
         .with_header("Transfer-Encoding", "chunked")
         .with_header("Content-Type", "application/json")
         .with_header("Connection", "close");
-        
 
-    writer.write_status(&final_response.status).await
-        .map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})?;
-    writer.write_headers(&final_response.headers).await
-        .map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})?;
+    // hashed as the chunks go by instead of keeping a copy of the whole body
+    writer.add_trailer(Box::new(Sha256Trailer::new("X-Content-SHA256")))?;
+    writer.add_trailer(Box::new(LengthTrailer::new("X-Content-Length")))?;
+    writer.write_status(&final_response.status).await?;
+    writer.write_headers(&final_response.headers).await?;
 
-    let mut body_copy = Vec::new();
-    while let Some(chunk) = dest_response.chunk().await.map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})? {
+    while let Some(chunk) = dest_response.chunk().await? {
         println!("Forwarding chunk of size {}", chunk.len());
-        body_copy.extend_from_slice(&chunk);
-        let chunk_bytes = chunk.to_vec();
-        writer.write_chunked_body(&chunk_bytes).await
-            .map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})?;
+        writer.write_chunked_body(&chunk).await?;
+        // pass each piece on as it comes rather than once the write buffer fills
+        writer.flush().await?;
     }
-    
-    writer.write_chunked_body_done().await
-        .map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})?;
-    
-    writer.write_trailers(&body_copy).await
-        .map_err(|e| HandlerError{ status_code: HttpStatus::InternalServerError, message: e.to_string()})?;
-    
+
+    writer.write_body_done().await?;
+
     Ok(())
 }
 
-pub async fn dispatch_handler(writer: &mut ResponseWriter, req: &HttpRequest) -> Result<(), HandlerError> {
-    if let Some(rl) = &req.request_line {
-        match rl.target.as_str() {
-            s if s.starts_with("/yourproblem") => {
-                let resp = HttpResponse::new()
-                    .with_status(HttpStatus::BadRequest)
-                    .with_body(BAD_REQUEST_BODY)
-                    .with_default_headers()
-                    .with_header("Content-Type", "text/html");
-                    writer.write_all(&resp).await
-                        .map_err(|e| HandlerError { status_code: HttpStatus::InternalServerError, message: e.to_string() })
-            },
-            s if s.starts_with("/myproblem") => {
-                let resp = HttpResponse::new()
-                    .with_status(HttpStatus::InternalServerError)
-                    .with_body(INTERNAL_ERROR_BODY)
-                    .with_default_headers()
-                    .with_header("Content-Type", "text/html");
-                    writer.write_all(&resp).await
-                        .map_err(|e| HandlerError { status_code: HttpStatus::InternalServerError, message: e.to_string() })
-            },
-            s if s.starts_with("/httpbin") => proxy_handler(writer, req).await,
-            s if s.starts_with("/video") => video_handler(writer, req).await,
-            _ => default_handler(writer, req).await,
+pub async fn dispatch_handler(writer: &mut ResponseWriter, req: &mut HttpRequest) -> Result<(), HandlerError> {
+    // routes match regardless of case; handlers still see the target as sent
+    if let Some(route) = req.request_line.as_ref().map(|rl| rl.path().to_ascii_lowercase()) {
+        match route.as_str() {
+            s if s.starts_with("/yourproblem") => writer.respond((HttpStatus::BadRequest, Html(BAD_REQUEST_BODY))).await?,
+            s if s.starts_with("/myproblem") => writer.respond((HttpStatus::InternalServerError, Html(INTERNAL_ERROR_BODY))).await?,
+            s if s.starts_with("/httpbin") => proxy_handler(writer, req).await?,
+            s if s.starts_with("/video") => video_handler(writer, req).await?,
+            s if s.starts_with("/upload") => writer.respond(upload_handler(req).await).await?,
+            s if s.starts_with("/form") => writer.respond(form_handler(req).await).await?,
+            _ if req.match_route("/greet/{name}") => writer.respond(greet_handler(req).await).await?,
+            _ => writer.respond(default_handler(req).await).await?,
         }
+        Ok(())
     } else {
         Err(HandlerError{
                 status_code: HttpStatus::InternalServerError,
                 message: "No request line found".to_string(),
             })
     }
+}
 
-    
-}
\ No newline at end of file
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::request::{HttpMethod, HttpVersion, RequestLine};
+    use tokio::io::AsyncReadExt;
+
+    async fn dispatch(method: HttpMethod, target: &str, body: &str) -> String {
+        let mut req = HttpRequest::new()
+            .with_request_line(RequestLine { method, target: target.to_string(), version: HttpVersion::HTTP11 })
+            .with_body(body.as_bytes().to_vec());
+        let (server, mut client) = tokio::io::duplex(64 * 1024);
+        let mut writer = ResponseWriter::boxed(server).with_request(&req);
+        dispatch_handler(&mut writer, &mut req).await.unwrap();
+        drop(writer);
+        let mut sent = String::new();
+        client.read_to_string(&mut sent).await.unwrap();
+        sent
+    }
+
+    #[tokio::test]
+    async fn handlers_run_without_a_socket() {
+        let sent = dispatch(HttpMethod::Get, "/greet/ada?greeting=Howdy", "").await;
+        assert!(sent.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(sent.ends_with("\r\n\r\nHowdy, ada!\n"));
+        // literal segments match whatever their case; captured ones keep it
+        let sent = dispatch(HttpMethod::Get, "/GREET/Alice", "").await;
+        assert!(sent.ends_with("\r\n\r\nHello, Alice!\n"), "{}", sent);
+
+        let sent = dispatch(HttpMethod::Post, "/upload", "hello").await;
+        assert!(sent.ends_with("received 5 bytes, sha256 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\n"));
+
+        let sent = dispatch(HttpMethod::Get, "/yourproblem", "").await;
+        assert!(sent.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
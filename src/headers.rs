@@ -1,75 +1,289 @@
 use core::fmt;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::ops::Range;
 
 use anyhow::{bail, Result};
+use bytes::Bytes;
 
-#[derive(Debug, Clone)]
-pub struct Headers(pub HashMap<String, String>);
+use crate::typed_headers::{Header, HeaderError};
+
+/// Header fields in the order they were added, names kept in the case they
+/// were given but matched without regard to it. A name can appear more than
+/// once, as `Set-Cookie` needs to. Fields parsed off the wire keep sharing
+/// the buffer they were read into.
+#[derive(Debug, Clone, Default)]
+pub struct Headers(Vec<RawHeader>);
 
 impl Headers {
     pub fn new() -> Self {
-        Self(HashMap::new())
+        Self(Vec::new())
     }
 
+    /// Sets `k` to `v`, replacing every existing field of that name. The new
+    /// field takes the place of the first one it replaces.
     pub fn insert(&mut self, k: &str, v: &str) -> Option<String> {
-        self.0.insert(k.to_string(), v.to_string())
+        let mut old = None;
+        let mut fields = Vec::with_capacity(self.0.len() + 1);
+        for field in self.0.drain(..) {
+            if !field.name().eq_ignore_ascii_case(k) {
+                fields.push(field);
+            } else if old.is_none() {
+                old = Some(field.text().to_string());
+                fields.push(RawHeader::new(k, v));
+            }
+        }
+        if old.is_none() {
+            fields.push(RawHeader::new(k, v));
+        }
+        self.0 = fields;
+        old
     }
 
-    pub fn get(&self, k: &str) -> Option<&String> {
-        self.0.get(k.to_lowercase().as_str())
+    /// Adds a field without touching any existing ones of the same name.
+    pub fn append(&mut self, k: &str, v: &str) {
+        self.0.push(RawHeader::new(k, v));
     }
 
-    #[cfg(test)]
-    pub fn len(&self) -> usize {
-        self.0.len()
+    /// Adds a parsed field as it is, without copying it out of its buffer
+    /// unless its value isn't UTF-8.
+    pub fn append_raw(&mut self, field: RawHeader) {
+        let field = match std::str::from_utf8(&field.value) {
+            Ok(_) => field,
+            Err(_) => RawHeader { value: Bytes::from(field.value().into_owned()), ..field },
+        };
+        self.0.push(field);
     }
 
-    pub fn parse_headers(data: &[u8]) -> Result<(Option<(String, String)>, usize)> {
+    /// The first value for `k`.
+    pub fn get(&self, k: &str) -> Option<&str> {
+        self.get_all(k).next()
+    }
 
-        // now convert headers to a string
-        let header_str = String::from_utf8_lossy(data).to_string();
-        // split that on '\r\n'
-        if let Some((raw_header, _rest)) = header_str.split_once("\r\n") {
-            let p = raw_header.len() + 2;
-            let raw_header = raw_header.trim();
-            if raw_header.is_empty() {
-                return Ok((None, p));
-            }
-            if let Some((field_name, field_value)) = raw_header.split_once(':') {
-                if field_name.trim_end() != field_name {
-                    bail!("Field name included invalid whitespace: '{}'", raw_header);
-                }
-                if !Self::valid_field_name(field_name) {
-                    bail!("Invalid characters detected: '{}'", field_name);
+    pub fn get_all<'a>(&'a self, k: &str) -> impl Iterator<Item = &'a str> {
+        self.0.iter().filter(move |field| field.name().eq_ignore_ascii_case(k)).map(RawHeader::text)
+    }
+
+    /// Every value for a list-valued field like `Accept-Encoding`, joined with
+    /// commas as if they had been sent on one line.
+    pub fn get_combined(&self, k: &str) -> Option<Cow<'_, str>> {
+        let mut values = self.get_all(k);
+        let first = values.next()?;
+        match values.next() {
+            None => Some(Cow::Borrowed(first)),
+            Some(second) => {
+                let mut combined = format!("{}, {}", first, second);
+                for value in values {
+                    combined.push_str(", ");
+                    combined.push_str(value);
                 }
-                Ok((Some((field_name.trim().to_lowercase(), field_value.trim().to_string())), p))
-            } else {
-                bail!("No ':' found in raw_header: {}", raw_header);
-            }
-        } else {
-            Ok((None, 0))
+                Some(Cow::Owned(combined))
+            },
         }
     }
 
-    fn valid_field_name(s: &str) -> bool {
-        if s.is_empty() {
-            return false;
-        }
-        // checks for only valid characters
-        let upper_chars = 'A'..='Z';
-        let lower_chars = 'a'..='z';
-        let digits = '0'..='9';
-        let special_chars = ['!', '#', '$', '%', '&', '\'', '*', '+', '-', '.', '^', '_', '`', '|', '~'];
-        let valid_chars: Vec<char> = upper_chars.chain(lower_chars).chain(digits).chain(special_chars).collect();
-        
-        s.chars().all(|c| valid_chars.contains(&c))
+    /// Removes every field named `k`, returning the first one's value.
+    pub fn remove(&mut self, k: &str) -> Option<String> {
+        let old = self.get(k).map(str::to_string);
+        self.0.retain(|field| !field.name().eq_ignore_ascii_case(k));
+        old
+    }
+
+    pub fn contains(&self, k: &str) -> bool {
+        self.get(k).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|field| (field.name(), field.text()))
+    }
+
+    /// Parses `H` out of its field, treating a malformed value as absent.
+    pub fn typed<H: Header>(&self) -> Option<H> {
+        self.try_typed().ok().flatten()
+    }
+
+    pub fn try_typed<H: Header>(&self) -> Result<Option<H>, HeaderError> {
+        let value = if H::LIST {
+            self.get_combined(H::NAME)
+        } else {
+            self.get(H::NAME).map(Cow::Borrowed)
+        };
+        value.map(|v| H::decode(&v)).transpose()
+    }
+
+    /// Every well-formed `H`, one per field line, for fields like `Set-Cookie`.
+    pub fn typed_all<H: Header>(&self) -> Vec<H> {
+        self.get_all(H::NAME).filter_map(|v| H::decode(v).ok()).collect()
+    }
+
+    pub fn insert_typed<H: Header>(&mut self, header: &H) {
+        self.insert(H::NAME, &header.encode());
+    }
+
+    pub fn append_typed<H: Header>(&mut self, header: &H) {
+        self.append(H::NAME, &header.encode());
+    }
+
+    /// The number of fields, counting repeated names once per field.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Parses the field on the first line of `data`, sharing its buffer.
+    /// Returns how many bytes it took, CRLF included, and `None` for the
+    /// empty line that ends a header section. `(None, 0)` means the line
+    /// isn't complete yet.
+    pub fn parse_headers(data: &Bytes) -> Result<(Option<RawHeader>, usize)> {
+        let Some(line_end) = find_crlf(data, 0) else {
+            return Ok((None, 0));
+        };
+        Ok((RawHeader::parse(data.slice(..line_end))?, line_end + 2))
+    }
+}
+
+/// A header field as it came off the wire, sharing the buffer it was parsed
+/// out of rather than owning a copy.
+#[derive(Debug, Clone)]
+pub struct RawHeader {
+    name: Bytes,
+    value: Bytes,
+}
+
+impl RawHeader {
+    fn new(name: &str, value: &str) -> Self {
+        RawHeader { name: Bytes::copy_from_slice(name.as_bytes()), value: Bytes::copy_from_slice(value.as_bytes()) }
+    }
+
+    /// Parses one line, without its CRLF. `None` for the empty line.
+    pub fn parse(line: Bytes) -> Result<Option<Self>> {
+        Ok(field_bounds(&line)?.map(|(name_end, value)| RawHeader {
+            name: line.slice(..name_end),
+            value: line.slice(value),
+        }))
+    }
+
+    pub fn name(&self) -> &str {
+        // field_bounds only lets token characters through
+        std::str::from_utf8(&self.name).unwrap_or_default()
+    }
+
+    pub fn value_bytes(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// The value as text, only allocating if it has bytes that aren't UTF-8.
+    pub fn value(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.value)
+    }
+
+    // the value of a field in `Headers`, which only holds UTF-8 ones
+    fn text(&self) -> &str {
+        std::str::from_utf8(&self.value).unwrap_or_default()
+    }
+}
+
+/// Offset of the first CRLF in `data` at or after `from`.
+pub fn find_crlf(data: &[u8], from: usize) -> Option<usize> {
+    let from = from.min(data.len());
+    data[from..].windows(2).position(|two_bytes| two_bytes == b"\r\n").map(|p| p + from)
+}
+
+// Splits a field line into the end of its name and the range of its value,
+// OWS trimmed. The name has to be a token right up to the colon.
+fn field_bounds(line: &[u8]) -> Result<Option<(usize, Range<usize>)>> {
+    if line.is_empty() {
+        return Ok(None);
+    }
+    // a continuation line would be read differently by whoever's in front of us
+    if line[0] == b' ' || line[0] == b'\t' {
+        bail!("Obsolete line folding: '{}'", String::from_utf8_lossy(line));
     }
+    let Some(colon) = line.iter().position(|b| *b == b':') else {
+        bail!("No ':' found in raw_header: {}", String::from_utf8_lossy(line));
+    };
+    if colon == 0 || !line[..colon].iter().all(|b| is_token_byte(*b)) {
+        bail!("Invalid field name: '{}'", String::from_utf8_lossy(&line[..colon]));
+    }
+    // bare CR and LF included, so nothing can end a line but CRLF
+    if line[colon + 1..].iter().any(|b| b.is_ascii_control() && *b != b'\t') {
+        bail!("Control characters in value of '{}'", String::from_utf8_lossy(&line[..colon]));
+    }
+    let is_ows = |b: &u8| *b == b' ' || *b == b'\t';
+    let start = line[colon + 1..].iter().position(|b| !is_ows(b)).map_or(line.len(), |p| p + colon + 1);
+    let end = line.iter().rposition(|b| !is_ows(b)).map_or(start, |p| (p + 1).max(start));
+    Ok(Some((colon, start..end)))
+}
+
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
 }
 
 impl fmt::Display for Headers {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f,"{}",
-            self.0.iter().map(|(k,v)| format!("{}: {}\r\n", k, v)).collect::<String>()
+            self.iter().map(|(k,v)| format!("{}: {}\r\n", k, v)).collect::<String>()
         )
     }
-}
\ No newline at end of file
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Box<dyn Iterator<Item = (&'a str, &'a str)> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn multi_valued_fields() {
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", "text/plain");
+        headers.append("Set-Cookie", "a=1");
+        headers.append("set-cookie", "b=2");
+        headers.insert("X-Trace", "1");
+
+        assert_eq!(headers.get("content-type"), Some("text/plain"));
+        assert_eq!(headers.get_all("SET-COOKIE").collect::<Vec<_>>(), ["a=1", "b=2"]);
+        assert_eq!(headers.get_combined("Set-Cookie").as_deref(), Some("a=1, b=2"));
+        assert_eq!(headers.to_string(), "Content-Type: text/plain\r\nSet-Cookie: a=1\r\nset-cookie: b=2\r\nX-Trace: 1\r\n");
+
+        // insert replaces every field of the name, in the first one's place
+        assert_eq!(headers.insert("Set-Cookie", "c=3"), Some("a=1".to_string()));
+        assert_eq!(headers.iter().collect::<Vec<_>>(), [("Content-Type", "text/plain"), ("Set-Cookie", "c=3"), ("X-Trace", "1")]);
+
+        assert_eq!(headers.remove("x-trace"), Some("1".to_string()));
+        assert!(!headers.contains("X-Trace"));
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn raw_fields_share_the_line() {
+        let line = Bytes::from_static(b"X-Trace:\t a b \t");
+        let field = RawHeader::parse(line.clone()).unwrap().unwrap();
+        assert_eq!((field.name(), field.value_bytes()), ("X-Trace", &b"a b"[..]));
+        assert_eq!(field.value_bytes().as_ptr(), line[10..].as_ptr());
+        assert!(RawHeader::parse(Bytes::new()).unwrap().is_none());
+        assert!(RawHeader::parse(Bytes::from_static(b"X-Trace :a")).is_err());
+        assert_eq!(find_crlf(b"a\r\nb\r\n", 2), Some(4));
+
+        // a header map built from parsed fields keeps pointing into the buffer, in the case sent
+        let data = Bytes::from_static(b"X-Trace: abc\r\nX-Odd: caf\xe9\r\n\r\n");
+        let mut headers = Headers::new();
+        let mut rest = data.clone();
+        while let (Some(field), consumed) = Headers::parse_headers(&rest).unwrap() {
+            headers.append_raw(field);
+            rest = rest.slice(consumed..);
+        }
+        assert_eq!(headers.iter().next(), Some(("X-Trace", "abc")));
+        assert_eq!(headers.get("x-trace").unwrap().as_ptr(), data[9..].as_ptr());
+        assert_eq!(headers.get("X-Odd"), Some("caf\u{fffd}"));
+    }
+}
@@ -0,0 +1,21 @@
+pub mod request;
+pub mod parser;
+pub mod response;
+pub mod trailers;
+pub mod status;
+pub mod headers;
+pub mod typed_headers;
+pub mod server;
+pub mod listener;
+#[cfg(unix)]
+pub mod systemd;
+pub mod handlers;
+pub mod range;
+pub mod file;
+pub mod conditional;
+pub mod compression;
+pub mod digest;
+pub mod body;
+pub mod sendfile;
+pub mod multipart;
+pub mod extract;
@@ -0,0 +1,293 @@
+use core::fmt;
+#[cfg(unix)]
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// An address for the server to listen on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bind {
+    /// One IPv4 or IPv6 address. An IPv6 one only takes IPv6 connections, so
+    /// the same port can be bound for IPv4 separately.
+    Tcp(SocketAddr),
+    /// Every address on the port, IPv4 and IPv6 alike, on one socket. Just
+    /// the IPv4 ones where IPv6 is turned off.
+    DualStack(u16),
+    /// A Unix domain socket at `path`, given `mode` permissions if set.
+    #[cfg(unix)]
+    Unix { path: PathBuf, mode: Option<u32> },
+}
+
+#[cfg(unix)]
+impl Bind {
+    pub fn unix(path: impl AsRef<Path>) -> Self {
+        Bind::Unix { path: path.as_ref().to_path_buf(), mode: None }
+    }
+
+    /// Sets the permissions of a Unix socket, like `0o660` to let a group in.
+    /// Does nothing for TCP.
+    pub fn with_mode(self, mode: u32) -> Self {
+        match self {
+            Bind::Unix { path, .. } => Bind::Unix { path, mode: Some(mode) },
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Bind::Tcp(addr) => write!(f, "{}", addr),
+            Bind::DualStack(port) => write!(f, "[::]:{} (dual-stack)", port),
+            #[cfg(unix)]
+            Bind::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Who's on the other end of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerInfo {
+    Tcp(SocketAddr),
+    /// The credentials of the process that connected, as the kernel saw
+    /// them at `connect` time.
+    Unix { pid: Option<i32>, uid: u32, gid: u32 },
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // an IPv4 client on a dual-stack socket shows up as ::ffff:a.b.c.d
+            PeerInfo::Tcp(addr) => write!(f, "{}", SocketAddr::new(addr.ip().to_canonical(), addr.port())),
+            PeerInfo::Unix { pid: Some(pid), uid, gid } => write!(f, "unix:pid={},uid={},gid={}", pid, uid, gid),
+            PeerInfo::Unix { pid: None, uid, gid } => write!(f, "unix:uid={},gid={}", uid, gid),
+        }
+    }
+}
+
+/// An accepted connection, still in whatever type its listener produced.
+#[derive(Debug)]
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// A bound socket the server accepts connections from.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    // the path is only kept when we created the socket file, so it's ours to remove
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    pub async fn bind(bind: &Bind) -> io::Result<Self> {
+        match bind {
+            Bind::Tcp(addr) => Ok(Listener::Tcp(tcp_listener(*addr, addr.is_ipv6())?)),
+            Bind::DualStack(port) => {
+                let listener = match tcp_listener(SocketAddr::from((Ipv6Addr::UNSPECIFIED, *port)), false) {
+                    // a host without IPv6 can't make or bind the socket, but IPv4 still works
+                    Err(e) if ipv6_unavailable(&e) => tcp_listener(SocketAddr::from((Ipv4Addr::UNSPECIFIED, *port)), false)?,
+                    listener => listener?,
+                };
+                Ok(Listener::Tcp(listener))
+            },
+            #[cfg(unix)]
+            Bind::Unix { path, mode } => Ok(Listener::Unix(unix_listener(path, *mode)?, Some(path.clone()))),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(Connection, PeerInfo)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (conn, addr) = listener.accept().await?;
+                Ok((Connection::Tcp(conn), PeerInfo::Tcp(addr)))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (conn, _) = listener.accept().await?;
+                let cred = conn.peer_cred()?;
+                let peer = PeerInfo::Unix { pid: cred.pid(), uid: cred.uid(), gid: cred.gid() };
+                Ok((Connection::Unix(conn), peer))
+            },
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<String> {
+        match self {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            // ours was bound elsewhere and moved into place, so the kernel's name for it is stale
+            #[cfg(unix)]
+            Listener::Unix(_, Some(path)) => Ok(format!("unix:{}", path.display())),
+            #[cfg(unix)]
+            Listener::Unix(listener, None) => Ok(match listener.local_addr()?.as_pathname() {
+                Some(path) => format!("unix:{}", path.display()),
+                None => "unix:(unnamed)".to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+// TCP sockets are set up by hand so IPV6_V6ONLY is whatever we asked for
+// rather than whatever the system default is
+fn tcp_listener(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+// IPv6 being turned off shows up as its address family or the wildcard
+// address being unavailable; anything else is a real failure to report
+fn ipv6_unavailable(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    const EAFNOSUPPORT: i32 = libc::EAFNOSUPPORT;
+    // WSAEAFNOSUPPORT
+    #[cfg(not(unix))]
+    const EAFNOSUPPORT: i32 = 10047;
+    e.kind() == io::ErrorKind::AddrNotAvailable || e.raw_os_error() == Some(EAFNOSUPPORT)
+}
+
+// Unix sockets are bound in a directory only we can get into and given their
+// mode there, then moved into place, so nobody connects while the umask's
+// permissions still apply
+#[cfg(unix)]
+fn unix_listener(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "something other than a socket is at that path"));
+        }
+        // a socket an earlier run left behind refuses connections and is bound over
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, "another server is listening on that socket")),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {},
+            Err(e) => return Err(e),
+        }
+    }
+    let Some(name) = path.file_name() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name"));
+    };
+    // named at random, since a crashed run's leftover may well have had our pid
+    let private = loop {
+        let suffix = RandomState::new().build_hasher().finish() as u32;
+        let private = path.with_file_name(format!(".{}.{:08x}", name.display(), suffix));
+        match std::fs::DirBuilder::new().mode(0o700).create(&private) {
+            Ok(()) => break private,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {},
+            Err(e) => return Err(e),
+        }
+    };
+    let staged = private.join("s");
+    let result = bind_staged(&staged, path, mode);
+    std::fs::remove_file(&staged).ok();
+    std::fs::remove_dir(&private).ok();
+    result
+}
+
+#[cfg(unix)]
+fn bind_staged(staged: &Path, path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(staged)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(staged, std::fs::Permissions::from_mode(mode))?;
+    }
+    std::fs::rename(staged, path)?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn binds_tcp() {
+        let listener = Listener::bind(&Bind::Tcp("127.0.0.1:0".parse().unwrap())).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(&addr).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, PeerInfo::Tcp(client.local_addr().unwrap()));
+
+        // IPv4 gets in whether or not the host has IPv6
+        let listener = Listener::bind(&Bind::DualStack(0)).await.unwrap();
+        let port = listener.local_addr().unwrap().rsplit_once(':').unwrap().1.parse::<u16>().unwrap();
+        let _client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.to_string().rsplit_once(':').unwrap().0, "127.0.0.1");
+
+        // only a missing IPv6 makes it settle for IPv4
+        #[cfg(unix)]
+        assert!(ipv6_unavailable(&io::Error::from_raw_os_error(libc::EAFNOSUPPORT)));
+        assert!(ipv6_unavailable(&io::ErrorKind::AddrNotAvailable.into()));
+        assert!(!ipv6_unavailable(&io::ErrorKind::PermissionDenied.into()));
+        if let Ok(taken) = Listener::bind(&Bind::Tcp("[::]:0".parse().unwrap())).await {
+            let port = taken.local_addr().unwrap().rsplit_once(':').unwrap().1.parse::<u16>().unwrap();
+            let err = Listener::bind(&Bind::DualStack(port)).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn binds_unix() {
+        let path = std::env::temp_dir().join(format!("listener-test-{}.sock", std::process::id()));
+        let listener = Listener::bind(&Bind::unix(&path).with_mode(0o600)).await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(listener.local_addr().unwrap(), format!("unix:{}", path.display()));
+        // nothing's left of where it was staged
+        let staging = format!(".{}.", path.file_name().unwrap().display());
+        let leftovers = std::fs::read_dir(path.parent().unwrap()).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with(&staging))
+            .count();
+        assert_eq!(leftovers, 0);
+
+        // a live server's socket is left alone
+        let err = Listener::bind(&Bind::unix(&path)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        let _client = UnixStream::connect(&path).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        let PeerInfo::Unix { pid, .. } = peer else { panic!("not a unix peer: {:?}", peer) };
+        assert_eq!(pid, Some(std::process::id() as i32));
+
+        drop(listener);
+        assert!(!path.exists());
+
+        // a socket file nobody's listening on any more is rebound over
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = Listener::bind(&Bind::unix(&path)).await.unwrap();
+        drop(listener);
+        assert!(!path.exists());
+
+        // but anything that isn't a socket is refused and left alone
+        std::fs::write(&path, "not a socket").unwrap();
+        let err = Listener::bind(&Bind::unix(&path)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,25 +1,45 @@
 use anyhow::Result;
 
-mod request;
-mod response;
-mod headers;
-mod server;
-mod handlers;
+const PORT: u16 = 42069;
+const MAX_DECODED_BODY: usize = 16 * 1024 * 1024;
 
-const PORT: usize = 42069;
+use rust_http_server::digest::DigestAlgorithm;
+use rust_http_server::handlers::check_expectation;
+use rust_http_server::listener::Bind;
+use rust_http_server::server::HttpServer;
+#[cfg(unix)]
+use rust_http_server::systemd::{self, ListenFds, Notifier};
+use tokio::sync::oneshot;
 
-use crate::server::HttpServer;
+fn main() -> Result<()> {
+    // taking the activated sockets clears their variables from the
+    // environment, which has to happen before the runtime starts threads
+    // SAFETY: nothing but this thread is running yet
+    #[cfg(unix)]
+    let activated = unsafe { systemd::listen_fds()? };
+    let runtime = tokio::runtime::Runtime::new()?;
+    #[cfg(unix)]
+    let (server, cancel_ch) = runtime.block_on(bind(activated))?;
+    #[cfg(not(unix))]
+    let (server, cancel_ch) = runtime.block_on(bind_ports())?;
+    runtime.block_on(serve(server, cancel_ch))
+}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let (mut server, cancel_ch) = HttpServer::serve(PORT).await?;
-    println!("Server started on port {}...", PORT);
+async fn serve(server: HttpServer, cancel_ch: oneshot::Sender<()>) -> Result<()> {
+    let mut server = server
+        .with_request_decompression(MAX_DECODED_BODY)
+        .with_expect_check(check_expectation)
+        .with_content_digest(DigestAlgorithm::Sha256);
+    println!("Server listening on {}...", server.local_addrs().join(", "));
 
     let handle = tokio::spawn(async move {
         server.listen().await
     });
 
-    tokio::signal::ctrl_c().await?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        result = terminated() => result?,
+    }
     cancel_ch.send(()).ok();
 
     match handle.await {
@@ -30,3 +50,41 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+// under socket activation systemd decides where we listen
+#[cfg(unix)]
+async fn bind(activated: ListenFds) -> Result<(HttpServer, oneshot::Sender<()>)> {
+    let activated = activated.into_sockets()?;
+    let (mut server, cancel_ch) = if activated.is_empty() {
+        bind_ports().await?
+    } else {
+        HttpServer::from_listeners(activated.into_iter().map(|socket| socket.listener).collect())
+    };
+    if let Some(notifier) = Notifier::from_env()? {
+        server = server.with_notifier(notifier);
+    }
+    Ok((server, cancel_ch))
+}
+
+async fn bind_ports() -> Result<(HttpServer, oneshot::Sender<()>)> {
+    #[allow(unused_mut)]
+    let mut binds = vec![Bind::DualStack(PORT)];
+    // sidecars reach us over a Unix socket instead
+    #[cfg(unix)]
+    if let Some(path) = std::env::var_os("UNIX_SOCKET") {
+        binds.push(Bind::unix(path).with_mode(0o660));
+    }
+    HttpServer::bind(&binds).await
+}
+
+// systemd stops services with SIGTERM
+#[cfg(unix)]
+async fn terminated() -> std::io::Result<()> {
+    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?.recv().await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn terminated() -> std::io::Result<()> {
+    std::future::pending().await
+}
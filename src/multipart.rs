@@ -0,0 +1,444 @@
+use core::fmt;
+use std::io;
+use std::path::Path;
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::body::RequestBody;
+use crate::headers::Headers;
+use crate::request::HttpRequest;
+use crate::typed_headers::{ContentType, Header, parse_parameterized};
+
+const READ_BUFFER_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MultipartLimits {
+    /// Largest body a single part may have.
+    pub max_part_size: u64,
+    /// Largest the whole multipart body may be, delimiters and headers included.
+    pub max_total_size: u64,
+    pub max_parts: usize,
+    /// Largest header section a single part may have.
+    pub max_header_size: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        Self {
+            max_part_size: 32 * 1024 * 1024,
+            max_total_size: 64 * 1024 * 1024,
+            max_parts: 64,
+            max_header_size: 8 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MultipartError {
+    NotMultipart,
+    Malformed(String),
+    PartTooLarge(u64),
+    TotalTooLarge(u64),
+    TooManyParts(usize),
+    Io(io::Error),
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MultipartError::NotMultipart => write!(f, "expected a multipart/form-data body with a boundary"),
+            MultipartError::Malformed(reason) => write!(f, "malformed multipart body: {}", reason),
+            MultipartError::PartTooLarge(limit) => write!(f, "multipart part exceeds {} bytes", limit),
+            MultipartError::TotalTooLarge(limit) => write!(f, "multipart body exceeds {} bytes", limit),
+            MultipartError::TooManyParts(limit) => write!(f, "multipart body has more than {} parts", limit),
+            MultipartError::Io(e) => write!(f, "multipart i/o error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MultipartError {}
+
+impl From<io::Error> for MultipartError {
+    fn from(e: io::Error) -> Self {
+        MultipartError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // looking for the first delimiter; anything before it is preamble
+    Preamble,
+    // just past a delimiter, deciding between another part and the close delimiter
+    Delimiter,
+    Headers,
+    Body,
+    End,
+}
+
+/// Incremental `multipart/form-data` reader over a request body. Parts are
+/// handed out one at a time and their contents streamed, so a large file
+/// never has to fit in memory.
+#[derive(Debug)]
+pub struct Multipart {
+    body: RequestBody,
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    state: State,
+    limits: MultipartLimits,
+    total_read: u64,
+    part_count: usize,
+    part_read: u64,
+}
+
+impl Multipart {
+    pub fn new(body: RequestBody, boundary: &str) -> Self {
+        Self {
+            body,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // lets the very first delimiter match the same CRLF-prefixed pattern as the rest
+            buffer: b"\r\n".to_vec(),
+            state: State::Preamble,
+            limits: MultipartLimits::default(),
+            total_read: 0,
+            part_count: 0,
+            part_read: 0,
+        }
+    }
+
+    /// Reads the boundary from `Content-Type` and takes over the request body,
+    /// whether it's still streaming or already buffered.
+    pub fn from_request(req: &mut HttpRequest) -> Result<Self, MultipartError> {
+        let boundary = req.headers.typed::<ContentType>()
+            .and_then(|ct| boundary_of(&ct))
+            .ok_or(MultipartError::NotMultipart)?;
+        let body = match req.take_body_stream() {
+            Some(stream) => stream,
+            None => RequestBody::new(io::Cursor::new(std::mem::take(&mut req.body))),
+        };
+        Ok(Self::new(body, &boundary))
+    }
+
+    pub fn with_limits(mut self, limits: MultipartLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Pulls more of the body into the buffer. Returns false at end of input.
+    async fn fill(&mut self) -> Result<bool, MultipartError> {
+        let mut read_buffer = [0u8; READ_BUFFER_SIZE];
+        let n = self.body.read(&mut read_buffer).await?;
+        self.total_read += n as u64;
+        if self.total_read > self.limits.max_total_size {
+            return Err(MultipartError::TotalTooLarge(self.limits.max_total_size));
+        }
+        self.buffer.extend_from_slice(&read_buffer[..n]);
+        Ok(n > 0)
+    }
+
+    async fn fill_or_fail(&mut self, context: &str) -> Result<(), MultipartError> {
+        if !self.fill().await? {
+            return Err(MultipartError::Malformed(format!("body ended {}", context)));
+        }
+        Ok(())
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack.windows(needle.len()).position(|window| window == needle)
+    }
+
+    /// Advances to the next part, skipping whatever is left of the current one.
+    pub async fn next_part(&mut self) -> Result<Option<Part<'_>>, MultipartError> {
+        loop {
+            match self.state {
+                State::End => return Ok(None),
+                State::Body => {
+                    while self.read_chunk().await?.is_some() {}
+                },
+                State::Preamble => match Self::find(&self.buffer, &self.delimiter) {
+                    Some(at) => {
+                        self.buffer.drain(..at + self.delimiter.len());
+                        self.state = State::Delimiter;
+                    },
+                    None => {
+                        // keep just enough of the tail to catch a delimiter split across reads
+                        let keep = self.delimiter.len() - 1;
+                        if self.buffer.len() > keep {
+                            self.buffer.drain(..self.buffer.len() - keep);
+                        }
+                        self.fill_or_fail("before the first boundary").await?;
+                    },
+                },
+                State::Delimiter => {
+                    // transport padding may sit between the delimiter and its CRLF
+                    let padding = self.buffer.iter().take_while(|b| **b == b' ' || **b == b'\t').count();
+                    let rest = &self.buffer[padding..];
+                    if rest.starts_with(b"--") {
+                        self.buffer.clear();
+                        self.state = State::End;
+                    } else if rest.starts_with(b"\r\n") {
+                        self.buffer.drain(..padding + 2);
+                        self.state = State::Headers;
+                    } else if rest.len() >= 2 {
+                        return Err(MultipartError::Malformed("boundary not followed by CRLF".to_string()));
+                    } else {
+                        self.fill_or_fail("after a boundary").await?;
+                    }
+                },
+                State::Headers => {
+                    // a part with no headers at all goes straight to the blank line
+                    let end = if self.buffer.starts_with(b"\r\n") {
+                        Some(2)
+                    } else {
+                        Self::find(&self.buffer, b"\r\n\r\n").map(|i| i + 4)
+                    };
+                    let Some(end) = end else {
+                        if self.buffer.len() > self.limits.max_header_size {
+                            return Err(MultipartError::Malformed(format!("part headers exceed {} bytes", self.limits.max_header_size)));
+                        }
+                        self.fill_or_fail("inside part headers").await?;
+                        continue;
+                    };
+
+                    self.part_count += 1;
+                    if self.part_count > self.limits.max_parts {
+                        return Err(MultipartError::TooManyParts(self.limits.max_parts));
+                    }
+
+                    // one copy of the header block, which the fields then share
+                    let headers = parse_part_headers(Bytes::copy_from_slice(&self.buffer[..end]))?;
+                    self.buffer.drain(..end);
+                    self.state = State::Body;
+                    self.part_read = 0;
+                    return Ok(Some(Part::new(self, headers)));
+                },
+            }
+        }
+    }
+
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, MultipartError> {
+        loop {
+            if self.state != State::Body {
+                return Ok(None);
+            }
+
+            let data = match Self::find(&self.buffer, &self.delimiter) {
+                Some(at) => {
+                    let data: Vec<u8> = self.buffer.drain(..at).collect();
+                    self.buffer.drain(..self.delimiter.len());
+                    self.state = State::Delimiter;
+                    data
+                },
+                None => {
+                    // anything that can't be the start of a delimiter is safe to hand out
+                    let safe = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+                    if safe == 0 {
+                        self.fill_or_fail("inside a part").await?;
+                        continue;
+                    }
+                    self.buffer.drain(..safe).collect()
+                },
+            };
+
+            self.part_read += data.len() as u64;
+            if self.part_read > self.limits.max_part_size {
+                return Err(MultipartError::PartTooLarge(self.limits.max_part_size));
+            }
+            if data.is_empty() {
+                return Ok(None);
+            }
+            return Ok(Some(data));
+        }
+    }
+}
+
+/// One part of a multipart body. Its contents can only be read until the
+/// next call to `Multipart::next_part`.
+#[derive(Debug)]
+pub struct Part<'a> {
+    multipart: &'a mut Multipart,
+    pub headers: Headers,
+    pub name: Option<String>,
+    pub filename: Option<String>,
+}
+
+impl<'a> Part<'a> {
+    fn new(multipart: &'a mut Multipart, headers: Headers) -> Self {
+        let disposition = headers.get("content-disposition").map(parse_parameterized);
+        let (name, filename) = match disposition {
+            Some((_, params)) => {
+                let param = |key: &str| params.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+                (param("name"), param("filename*").or_else(|| param("filename")))
+            },
+            None => (None, None),
+        };
+        Self { multipart, headers, name, filename }
+    }
+
+    /// Defaults to `text/plain` as RFC 7578 says.
+    pub fn content_type(&self) -> &str {
+        self.headers.get("content-type").unwrap_or("text/plain")
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+
+    pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>, MultipartError> {
+        self.multipart.read_chunk().await
+    }
+
+    /// Buffers the rest of the part. Meant for small text fields.
+    pub async fn bytes(&mut self) -> Result<Vec<u8>, MultipartError> {
+        let mut data = Vec::new();
+        while let Some(chunk) = self.chunk().await? {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    pub async fn text(&mut self) -> Result<String, MultipartError> {
+        String::from_utf8(self.bytes().await?)
+            .map_err(|_| MultipartError::Malformed("part is not valid UTF-8".to_string()))
+    }
+
+    /// Streams the rest of the part into `sink`, returning how many bytes went in.
+    pub async fn copy_to<W: AsyncWrite + Unpin>(&mut self, sink: &mut W) -> Result<u64, MultipartError> {
+        let mut written = 0;
+        while let Some(chunk) = self.chunk().await? {
+            sink.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        sink.flush().await?;
+        Ok(written)
+    }
+
+    pub async fn save_to(&mut self, path: impl AsRef<Path>) -> Result<u64, MultipartError> {
+        let mut f = tokio::fs::File::create(path).await?;
+        self.copy_to(&mut f).await
+    }
+}
+
+pub fn boundary_from_content_type(content_type: &str) -> Option<String> {
+    boundary_of(&ContentType::decode(content_type).ok()?)
+}
+
+fn boundary_of(content_type: &ContentType) -> Option<String> {
+    if !content_type.is("multipart/form-data") {
+        return None;
+    }
+    content_type.param("boundary")
+        .filter(|b| !b.is_empty() && b.len() <= 70)
+        .map(str::to_string)
+}
+
+fn parse_part_headers(data: Bytes) -> Result<Headers, MultipartError> {
+    let mut headers = Headers::new();
+    let mut rest = data;
+    loop {
+        match Headers::parse_headers(&rest).map_err(|e| MultipartError::Malformed(e.to_string()))? {
+            (Some(field), consumed) => {
+                // a repeated part header replaces the one before it
+                headers.remove(field.name());
+                headers.append_raw(field);
+                rest = rest.slice(consumed..);
+            },
+            (None, _) => return Ok(headers),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble to ignore\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"title\"\r\n\
+\r\n\
+Sunset\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"shot\"; filename=\"sun set.png\"\r\n\
+Content-Type: image/png\r\n\
+\r\n\
+\x89PNG\r\n--Xy not quite a boundary\r\n\
+--XyZ  \r\n\
+Content-Disposition: form-data; name=\"empty\"\r\n\
+\r\n\
+\r\n\
+--XyZ--\r\n\
+epilogue";
+
+    #[tokio::test]
+    async fn parse_parts() {
+        let mut multipart = Multipart::new(RequestBody::new(io::Cursor::new(BODY.to_vec())), "XyZ");
+
+        let mut title = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(title.name.as_deref(), Some("title"));
+        assert!(!title.is_file());
+        assert_eq!(title.content_type(), "text/plain");
+        assert_eq!(title.text().await.unwrap(), "Sunset");
+
+        let mut shot = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(shot.name.as_deref(), Some("shot"));
+        assert_eq!(shot.filename.as_deref(), Some("sun set.png"));
+        assert_eq!(shot.content_type(), "image/png");
+        let mut sink = Vec::new();
+        assert_eq!(shot.copy_to(&mut sink).await.unwrap(), 31);
+        assert_eq!(sink, b"\x89PNG\r\n--Xy not quite a boundary");
+
+        let mut empty = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(empty.name.as_deref(), Some("empty"));
+        assert_eq!(empty.bytes().await.unwrap(), b"");
+
+        assert!(multipart.next_part().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn skips_unread_parts_and_enforces_limits() {
+        let mut multipart = Multipart::new(RequestBody::new(io::Cursor::new(BODY.to_vec())), "XyZ");
+        let names = {
+            let mut names = Vec::new();
+            while let Some(part) = multipart.next_part().await.unwrap() {
+                names.push(part.name.clone().unwrap());
+            }
+            names
+        };
+        assert_eq!(names, ["title", "shot", "empty"]);
+
+        let limits = MultipartLimits { max_part_size: 10, ..MultipartLimits::default() };
+        let mut multipart = Multipart::new(RequestBody::new(io::Cursor::new(BODY.to_vec())), "XyZ").with_limits(limits);
+        multipart.next_part().await.unwrap();
+        let err = multipart.next_part().await.unwrap().unwrap().bytes().await.unwrap_err();
+        assert!(matches!(err, MultipartError::PartTooLarge(10)));
+
+        let limits = MultipartLimits { max_parts: 1, ..MultipartLimits::default() };
+        let mut multipart = Multipart::new(RequestBody::new(io::Cursor::new(BODY.to_vec())), "XyZ").with_limits(limits);
+        multipart.next_part().await.unwrap();
+        assert!(matches!(multipart.next_part().await, Err(MultipartError::TooManyParts(1))));
+
+        let limits = MultipartLimits { max_total_size: 64, ..MultipartLimits::default() };
+        let mut multipart = Multipart::new(RequestBody::new(io::Cursor::new(BODY.to_vec())), "XyZ").with_limits(limits);
+        assert!(matches!(multipart.next_part().await, Err(MultipartError::TotalTooLarge(64))));
+
+        let mut truncated = Multipart::new(RequestBody::new(io::Cursor::new(BODY[..120].to_vec())), "XyZ");
+        truncated.next_part().await.unwrap();
+        assert!(matches!(truncated.next_part().await, Err(MultipartError::Malformed(_))));
+    }
+
+    #[test]
+    fn content_disposition_params() {
+        let (kind, params) = parse_parameterized("form-data; name=\"a;b\"; filename*=UTF-8''%E2%82%AC%20rates.txt; filename=\"fallback\\\".txt\"");
+        assert_eq!(kind, "form-data");
+        assert_eq!(params, [
+            ("name".to_string(), "a;b".to_string()),
+            ("filename*".to_string(), "€ rates.txt".to_string()),
+            ("filename".to_string(), "fallback\".txt".to_string()),
+        ]);
+
+        assert_eq!(boundary_from_content_type("multipart/form-data; boundary=----WebKit123").as_deref(), Some("----WebKit123"));
+        assert_eq!(boundary_from_content_type("Multipart/Form-Data; charset=utf-8; boundary=\"a b\"").as_deref(), Some("a b"));
+        assert_eq!(boundary_from_content_type("application/json"), None);
+        assert_eq!(boundary_from_content_type("multipart/form-data"), None);
+    }
+}
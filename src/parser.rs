@@ -0,0 +1,245 @@
+use anyhow::{bail, Result};
+use bytes::{Bytes, BytesMut};
+
+use crate::body::{BodyDecoder, BodyEvent, Framing};
+use crate::compression::ContentCoding;
+use crate::headers::{find_crlf, Headers, RawHeader};
+use crate::request::RequestLine;
+use crate::typed_headers::{ContentLength, Host};
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+// the only fields the parser itself needs to look at
+const FRAMING_FIELDS: [&str; 3] = ["host", "content-length", "transfer-encoding"];
+
+/// Something the parser recognised in the bytes fed to it, in wire order.
+#[derive(Debug, Clone)]
+pub enum Event {
+    RequestLine(RequestLine),
+    Header(RawHeader),
+    /// The header section is over and this is how the body is delimited.
+    /// `transfer_codings` are the ones applied on top of chunked, outermost last.
+    HeadersComplete { framing: Framing, transfer_codings: Vec<ContentCoding> },
+    BodyChunk(Bytes),
+    Trailers(Headers),
+    End,
+}
+
+#[derive(Debug)]
+enum State {
+    RequestLine,
+    Headers,
+    HeadComplete,
+    Body(BodyDecoder),
+    Done,
+}
+
+/// A push parser for one HTTP/1.1 request. It does no I/O of its own: bytes
+/// go in through `feed` in whatever pieces they arrive in, and come back out
+/// as the events they complete. Every byte is scanned once, and the lines
+/// handed out share the buffer they were read into.
+#[derive(Debug)]
+pub struct Parser {
+    state: State,
+    buffer: BytesMut,
+    // how much of `buffer` is known not to hold a CRLF
+    scanned: usize,
+    head_size: usize,
+    framing_headers: Headers,
+    head_only: bool,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self {
+            state: State::RequestLine,
+            buffer: BytesMut::new(),
+            scanned: 0,
+            head_size: 0,
+            framing_headers: Headers::new(),
+            head_only: false,
+        }
+    }
+
+    /// A parser that stops at `HeadersComplete`, leaving the body undecoded
+    /// in `into_leftover` for whoever reads it next.
+    pub fn head_only() -> Self {
+        Self { head_only: true, ..Self::new() }
+    }
+
+    pub fn is_head_complete(&self) -> bool {
+        !matches!(self.state, State::RequestLine | State::Headers)
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::HeadComplete | State::Done)
+    }
+
+    /// Bytes fed past the point where parsing stopped: the body of a
+    /// head-only parse, or whatever followed the end of the request.
+    pub fn into_leftover(self) -> Vec<u8> {
+        match self.state {
+            State::Body(decoder) => decoder.into_leftover(),
+            _ => self.buffer.to_vec(),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<Event>> {
+        match &mut self.state {
+            State::Body(decoder) => decoder.extend(data),
+            _ => self.buffer.extend_from_slice(data),
+        }
+
+        let mut events = Vec::new();
+        loop {
+            match &mut self.state {
+                State::RequestLine => {
+                    let Some(line) = self.next_line() else { break };
+                    events.push(Event::RequestLine(RequestLine::parse_line(&line)?));
+                    self.state = State::Headers;
+                },
+                State::Headers => {
+                    let Some(line) = self.next_line() else { break };
+                    if let Some(field) = RawHeader::parse(line)? {
+                        if FRAMING_FIELDS.iter().any(|name| name.eq_ignore_ascii_case(field.name())) {
+                            self.framing_headers.append_raw(field.clone());
+                        }
+                        events.push(Event::Header(field));
+                        continue;
+                    }
+                    let (framing, transfer_codings) = framing_of(&self.framing_headers)?;
+                    events.push(Event::HeadersComplete { framing, transfer_codings });
+                    self.state = if self.head_only {
+                        State::HeadComplete
+                    } else {
+                        let mut decoder = BodyDecoder::new(framing);
+                        decoder.extend(&self.buffer.split());
+                        State::Body(decoder)
+                    };
+                },
+                State::Body(decoder) => match decoder.next_event(usize::MAX)? {
+                    Some(BodyEvent::Data(data)) => events.push(Event::BodyChunk(data)),
+                    Some(BodyEvent::Trailers(trailers)) => events.push(Event::Trailers(trailers)),
+                    Some(BodyEvent::End) => {
+                        events.push(Event::End);
+                        let decoder = std::mem::replace(decoder, BodyDecoder::new(Framing::None));
+                        self.buffer = BytesMut::from(&decoder.into_leftover()[..]);
+                        self.state = State::Done;
+                    },
+                    None => break,
+                },
+                State::HeadComplete | State::Done => break,
+            }
+        }
+
+        if !self.is_head_complete() && self.head_size + self.buffer.len() > MAX_HEAD_SIZE {
+            bail!("request head exceeds {} bytes", MAX_HEAD_SIZE);
+        }
+        Ok(events)
+    }
+
+    // Splits the next complete line of the head off the buffer, without its
+    // CRLF. Picks up the search for the CRLF where the last one left off.
+    fn next_line(&mut self) -> Option<Bytes> {
+        let Some(line_end) = find_crlf(&self.buffer, self.scanned.saturating_sub(1)) else {
+            self.scanned = self.buffer.len();
+            return None;
+        };
+        let mut line = self.buffer.split_to(line_end + 2).freeze();
+        line.truncate(line_end);
+        self.scanned = 0;
+        self.head_size += line_end + 2;
+        Some(line)
+    }
+}
+
+// Decides how the body is delimited once the header section is in, per
+// RFC 9112 section 6.3. Anything a proxy in front of us might read
+// differently is an error rather than a guess.
+fn framing_of(headers: &Headers) -> Result<(Framing, Vec<ContentCoding>)> {
+    match headers.get_all("host").count() {
+        0 => bail!("missing Host header"),
+        1 => { headers.try_typed::<Host>()?; },
+        _ => bail!("more than one Host header"),
+    }
+
+    let content_length = headers.try_typed::<ContentLength>()?;
+    let Some(transfer_encoding) = headers.get_combined("transfer-encoding") else {
+        return Ok((content_length.map_or(Framing::None, |ContentLength(length)| Framing::Length(length)), Vec::new()));
+    };
+    if content_length.is_some() {
+        bail!("both Content-Length and Transfer-Encoding present");
+    }
+    let mut codings: Vec<String> = transfer_encoding.split(',')
+        .map(|coding| coding.trim().to_lowercase())
+        .filter(|coding| !coding.is_empty())
+        .collect();
+    // without chunked last the body would run until the connection closes
+    if codings.pop().as_deref() != Some("chunked") {
+        bail!("Transfer-Encoding must end in chunked: '{}'", transfer_encoding);
+    }
+    let mut transfer_codings = Vec::new();
+    for coding in codings {
+        match ContentCoding::from_token(&coding) {
+            Some(ContentCoding::Identity) | None => bail!("unsupported transfer coding: '{}'", coding),
+            Some(coding) => transfer_codings.push(coding),
+        }
+    }
+    Ok((Framing::Chunked, transfer_codings))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn feed_in_pieces(data: &[u8], step: usize) -> Vec<Event> {
+        let mut parser = Parser::new();
+        let mut events = Vec::new();
+        for piece in data.chunks(step) {
+            events.extend(parser.feed(piece).unwrap());
+        }
+        assert!(parser.is_done());
+        events
+    }
+
+    #[test]
+    fn events_at_any_split() {
+        let data = b"POST /Upload?a=B HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5;x=y\r\nHello\r\n6\r\n World\r\n0\r\nX-Sum: 1\r\n\r\n";
+        for step in 1..data.len() {
+            let events = feed_in_pieces(data, step);
+            let Some(Event::RequestLine(rl)) = events.first() else { panic!("no request line") };
+            assert_eq!(rl.target, "/Upload?a=B");
+            assert!(matches!(&events[1], Event::Header(field) if field.name() == "Host" && field.value() == "localhost"));
+            assert!(events.iter().any(|e| matches!(e, Event::HeadersComplete { framing: Framing::Chunked, .. })));
+            let body: Vec<u8> = events.iter()
+                .filter_map(|e| match e { Event::BodyChunk(data) => Some(data.clone()), _ => None })
+                .flatten()
+                .collect();
+            assert_eq!(body, b"Hello World", "step {}", step);
+            assert!(matches!(&events[events.len() - 2], Event::Trailers(t) if t.get("x-sum").is_some()));
+            assert!(matches!(events.last(), Some(Event::End)));
+        }
+    }
+
+    #[test]
+    fn head_only_leaves_the_body() {
+        let mut parser = Parser::head_only();
+        let events = parser.feed(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        assert!(matches!(events.last(), Some(Event::HeadersComplete { framing: Framing::Length(5), .. })));
+        assert!(parser.is_done());
+        assert_eq!(parser.into_leftover(), b"hello");
+
+        let mut parser = Parser::new();
+        let events = parser.feed(b"GET / HTTP/1.1\r\nHost: a\r\n\r\nGET /next").unwrap();
+        assert!(matches!(events.last(), Some(Event::End)));
+        assert_eq!(parser.into_leftover(), b"GET /next");
+
+        let mut parser = Parser::new();
+        assert!(parser.feed(&vec![b'a'; MAX_HEAD_SIZE + 1]).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+
+use crate::conditional::EntityTag;
+use crate::request::{HttpMethod, HttpRequest};
+
+// Requests asking for more ranges than this are served in full instead. Lots of
+// tiny ranges cost far more to frame than they save.
+const MAX_RANGES: usize = 16;
+
+/// A single `range-spec` from a `Range: bytes=...` header, before it has been
+/// resolved against the length of the representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeSpec {
+    /// `first-last`
+    Bounded(u64, u64),
+    /// `first-`
+    From(u64),
+    /// `-length`
+    Suffix(u64),
+}
+
+impl RangeSpec {
+    pub fn resolve(&self, complete_length: u64) -> Option<ByteRange> {
+        match *self {
+            RangeSpec::Bounded(first, last) if first < complete_length => Some(ByteRange {
+                start: first,
+                end: last.min(complete_length - 1),
+            }),
+            RangeSpec::From(first) if first < complete_length => Some(ByteRange {
+                start: first,
+                end: complete_length - 1,
+            }),
+            RangeSpec::Suffix(length) if length > 0 && complete_length > 0 => Some(ByteRange {
+                start: complete_length - length.min(complete_length),
+                end: complete_length - 1,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A satisfiable byte range. Both ends are inclusive, as on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, complete_length: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, complete_length)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeOutcome {
+    Full,
+    Partial(Vec<ByteRange>),
+    NotSatisfiable,
+}
+
+pub fn parse_range(value: &str) -> Result<Vec<RangeSpec>> {
+    let (unit, range_set) = value.split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("invalid range: missing '=' in '{}'", value))?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        bail!("unsupported range unit: {}", unit);
+    }
+
+    let mut specs = Vec::new();
+    for raw_spec in range_set.split(',') {
+        let raw_spec = raw_spec.trim();
+        if raw_spec.is_empty() {
+            continue;
+        }
+        let (first, last) = raw_spec.split_once('-')
+            .ok_or_else(|| anyhow::anyhow!("invalid range spec: {}", raw_spec))?;
+        let spec = match (first, last) {
+            ("", "") => bail!("invalid range spec: {}", raw_spec),
+            ("", suffix) => RangeSpec::Suffix(parse_digits(suffix)?),
+            (first, "") => RangeSpec::From(parse_digits(first)?),
+            (first, last) => {
+                let (first, last) = (parse_digits(first)?, parse_digits(last)?);
+                if last < first {
+                    bail!("invalid range spec: {}", raw_spec);
+                }
+                RangeSpec::Bounded(first, last)
+            }
+        };
+        specs.push(spec);
+    }
+
+    if specs.is_empty() {
+        bail!("empty range set: {}", value);
+    }
+    Ok(specs)
+}
+
+fn parse_digits(s: &str) -> Result<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        bail!("invalid range position: '{}'", s);
+    }
+    Ok(s.parse::<u64>()?)
+}
+
+/// Decides how a request for a representation of `complete_length` bytes should
+/// be answered. A missing or malformed `Range`, or an `If-Range` that no longer
+/// matches, means the whole representation gets sent.
+pub fn evaluate(req: &HttpRequest, complete_length: u64, etag: Option<&EntityTag>, last_modified: Option<SystemTime>) -> RangeOutcome {
+    if req.request_line.as_ref().map(|rl| rl.method) != Some(HttpMethod::Get) {
+        return RangeOutcome::Full;
+    }
+    let Some(range) = req.headers.get("range") else {
+        return RangeOutcome::Full;
+    };
+    if let Some(if_range) = req.headers.get("if-range")
+        && !if_range_matches(if_range, etag, last_modified) {
+        return RangeOutcome::Full;
+    }
+
+    let specs = match parse_range(range) {
+        Ok(specs) if specs.len() <= MAX_RANGES => specs,
+        _ => return RangeOutcome::Full,
+    };
+    let ranges: Vec<ByteRange> = specs.iter()
+        .filter_map(|spec| spec.resolve(complete_length))
+        .collect();
+
+    if ranges.is_empty() {
+        RangeOutcome::NotSatisfiable
+    } else {
+        RangeOutcome::Partial(ranges)
+    }
+}
+
+// If-Range only ever matches on a strong comparison, whichever validator it carries
+fn if_range_matches(if_range: &str, etag: Option<&EntityTag>, last_modified: Option<SystemTime>) -> bool {
+    if let Ok(candidate) = EntityTag::parse(if_range) {
+        return etag.is_some_and(|etag| etag.strong_eq(&candidate));
+    }
+    let (Ok(since), Some(last_modified)) = (httpdate::parse_http_date(if_range.trim()), last_modified) else {
+        return false;
+    };
+    truncate_to_seconds(last_modified) == since
+}
+
+pub fn truncate_to_seconds(t: SystemTime) -> SystemTime {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + std::time::Duration::from_secs(d.as_secs()),
+        Err(_) => t,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::request::{HttpVersion, RequestLine};
+
+    fn get_with(headers: &[(&str, &str)]) -> HttpRequest {
+        let mut req = HttpRequest::new().with_request_line(RequestLine {
+            method: HttpMethod::Get,
+            target: "/video".to_string(),
+            version: HttpVersion::HTTP11,
+        });
+        for (k, v) in headers {
+            req = req.with_header(k, v);
+        }
+        req
+    }
+
+    #[test]
+    fn parse_range_specs() {
+        assert_eq!(parse_range("bytes=0-499").unwrap(), vec![RangeSpec::Bounded(0, 499)]);
+        assert_eq!(parse_range("bytes=9500-").unwrap(), vec![RangeSpec::From(9500)]);
+        assert_eq!(parse_range("bytes=-500").unwrap(), vec![RangeSpec::Suffix(500)]);
+        assert_eq!(
+            parse_range("bytes=0-0, -1 ,500-600").unwrap(),
+            vec![RangeSpec::Bounded(0, 0), RangeSpec::Suffix(1), RangeSpec::Bounded(500, 600)]
+        );
+
+        assert!(parse_range("items=0-1").is_err());
+        assert!(parse_range("bytes=").is_err());
+        assert!(parse_range("bytes=-").is_err());
+        assert!(parse_range("bytes=5-1").is_err());
+        assert!(parse_range("bytes=+1-2").is_err());
+        assert!(parse_range("bytes=a-b").is_err());
+    }
+
+    #[test]
+    fn resolve_against_length() {
+        assert_eq!(RangeSpec::Bounded(0, 499).resolve(1000), Some(ByteRange { start: 0, end: 499 }));
+        assert_eq!(RangeSpec::Bounded(900, 2000).resolve(1000), Some(ByteRange { start: 900, end: 999 }));
+        assert_eq!(RangeSpec::Bounded(1000, 2000).resolve(1000), None);
+        assert_eq!(RangeSpec::From(10).resolve(1000), Some(ByteRange { start: 10, end: 999 }));
+        assert_eq!(RangeSpec::Suffix(100).resolve(1000), Some(ByteRange { start: 900, end: 999 }));
+        assert_eq!(RangeSpec::Suffix(5000).resolve(1000), Some(ByteRange { start: 0, end: 999 }));
+        assert_eq!(RangeSpec::Suffix(0).resolve(1000), None);
+        assert_eq!(RangeSpec::Suffix(10).resolve(0), None);
+    }
+
+    #[test]
+    fn evaluate_requests() {
+        assert_eq!(evaluate(&get_with(&[]), 100, None, None), RangeOutcome::Full);
+        assert_eq!(evaluate(&get_with(&[("range", "bytes=abc")]), 100, None, None), RangeOutcome::Full);
+        assert_eq!(evaluate(&get_with(&[("range", "bytes=200-")]), 100, None, None), RangeOutcome::NotSatisfiable);
+        assert_eq!(
+            evaluate(&get_with(&[("range", "bytes=0-9,-10")]), 100, None, None),
+            RangeOutcome::Partial(vec![ByteRange { start: 0, end: 9 }, ByteRange { start: 90, end: 99 }])
+        );
+
+        let modified = UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_250);
+        let stamp = httpdate::fmt_http_date(modified);
+        let fresh = get_with(&[("range", "bytes=0-9"), ("if-range", &stamp)]);
+        assert_eq!(evaluate(&fresh, 100, None, Some(modified)), RangeOutcome::Partial(vec![ByteRange { start: 0, end: 9 }]));
+        let stale = get_with(&[("range", "bytes=0-9"), ("if-range", "Wed, 21 Oct 2015 07:28:00 GMT")]);
+        assert_eq!(evaluate(&stale, 100, None, Some(modified)), RangeOutcome::Full);
+
+        let by_etag = get_with(&[("range", "bytes=0-9"), ("if-range", "\"abc\"")]);
+        assert_eq!(evaluate(&by_etag, 100, None, Some(modified)), RangeOutcome::Full);
+        assert_eq!(
+            evaluate(&by_etag, 100, Some(&EntityTag::strong("abc")), Some(modified)),
+            RangeOutcome::Partial(vec![ByteRange { start: 0, end: 9 }])
+        );
+        assert_eq!(evaluate(&by_etag, 100, Some(&EntityTag::weak("abc")), Some(modified)), RangeOutcome::Full);
+    }
+}
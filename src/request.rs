@@ -1,17 +1,16 @@
 use core::fmt;
 use anyhow::{bail, Result};
 use tokio::io::AsyncReadExt;
-use crate::headers::Headers;
+use crate::body::{BodyReader, Framing, RequestBody, TrailerSlot};
+use crate::compression::{self, ContentCoding, DecodeError, DecodingReader};
+use crate::digest::{self, DigestError, VerifyingReader};
+use crate::headers::{find_crlf, Headers};
+use crate::listener::PeerInfo;
+use crate::parser::{Event, Parser};
 
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum ParserState {
-    Initialized,
-    ParsingHeaders,
-    ParsingBodyFull,
-    ParsingBodyChunked,
-    Done,
-}
+const READ_BUFFER_SIZE: usize = 1024;
+/// How much of a body extractors like `Json` buffer unless told otherwise.
+pub const DEFAULT_BODY_LIMIT: usize = 2 * 1024 * 1024;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HttpMethod {
@@ -19,6 +18,13 @@ pub enum HttpMethod {
     Post,
 }
 
+impl HttpMethod {
+    /// Whether the method only retrieves, leaving the target as it was.
+    pub fn is_safe(&self) -> bool {
+        matches!(self, HttpMethod::Get)
+    }
+}
+
 impl TryFrom<&str> for HttpMethod {
     type Error = anyhow::Error;
     fn try_from(value: &str) -> Result<Self> {
@@ -76,56 +82,95 @@ impl fmt::Display for RequestLine {
 }
 
 impl RequestLine {
+    pub fn path(&self) -> &str {
+        self.target.split_once('?').map_or(self.target.as_str(), |(path, _)| path)
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    /// Parses the request line at the start of `data`, returning it along
+    /// with how many bytes it took. `(None, 0)` until the line is complete.
     pub fn parse_request_line(data: &[u8]) -> Result<(Option<Self>, usize)> {
-        // read until a CRLF
-        let line_str = String::from_utf8_lossy(data).to_string();
-        if let Some((line, _rest)) = line_str.split_once("\r\n") {
-            let p = line.len() + 2; // +2 accounts for '\r\n'
-            // split that on whitespace
-            let mut parts = line.split_whitespace();
-
-            let method_raw = parts.next()
-                .ok_or_else(|| anyhow::anyhow!("invalid http request line: missing method"))?;
-            let target_raw = parts.next()
-                .ok_or_else(|| anyhow::anyhow!("invalid http request line: missing target"))?;
-            let version_raw = parts.next()
-                .ok_or_else(|| anyhow::anyhow!("invalid http request line: missing version"))?;
-
-            let method = HttpMethod::try_from(method_raw)?;
-            let target = if target_raw.starts_with("/") {
-                target_raw.trim().to_lowercase()
-                } else {
-                    bail!("target must start with '/'")
-                };
-            let version = HttpVersion::try_from(version_raw)?;
-
-            Ok((Some(Self {
-                method,
-                target,
-                version,
-            }),p))
-        } else {
-            Ok((None, 0))
+        match find_crlf(data, 0) {
+            Some(line_end) => Ok((Some(Self::parse_line(&data[..line_end])?), line_end + 2)),
+            None => Ok((None, 0)),
         }
+    }
 
-    } 
+    /// Parses a request line without its CRLF.
+    pub fn parse_line(line: &[u8]) -> Result<Self> {
+        if line.iter().any(u8::is_ascii_control) {
+            bail!("invalid http request line: control characters");
+        }
+        let line = std::str::from_utf8(line)
+            .map_err(|_| anyhow::anyhow!("invalid http request line: not UTF-8"))?;
+        // split that on whitespace
+        let mut parts = line.split_whitespace();
+
+        let method_raw = parts.next()
+            .ok_or_else(|| anyhow::anyhow!("invalid http request line: missing method"))?;
+        let target_raw = parts.next()
+            .ok_or_else(|| anyhow::anyhow!("invalid http request line: missing target"))?;
+        let version_raw = parts.next()
+            .ok_or_else(|| anyhow::anyhow!("invalid http request line: missing version"))?;
+        if parts.next().is_some() {
+            bail!("invalid http request line: too many elements");
+        }
+
+        let method = HttpMethod::try_from(method_raw)?;
+        // kept as sent: path segments can carry IDs and tokens where case matters
+        if !target_raw.starts_with('/') {
+            bail!("target must start with '/'");
+        }
+        let target = target_raw.to_string();
+        let version = HttpVersion::try_from(version_raw)?;
+
+        Ok(Self {
+            method,
+            target,
+            version,
+        })
+    }
 }
 
 #[derive(Debug)]
 pub struct HttpRequest {
-    parser_state: ParserState,
     pub request_line: Option<RequestLine>,
     pub headers: Headers,
     pub body: Vec<u8>,
+    body_stream: Option<RequestBody>,
+    trailers: TrailerSlot,
+    // codings applied on top of chunked, outermost last
+    transfer_codings: Vec<ContentCoding>,
+    /// Values captured by the `{name}` segments of the last route matched.
+    pub params: Vec<(String, String)>,
+    /// Who sent the request, when it came in over a connection.
+    pub peer: Option<PeerInfo>,
+    /// The most extractors like `Json` and `Form` will buffer; a longer body
+    /// gets a 413.
+    pub body_limit: usize,
+}
+
+impl Default for HttpRequest {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HttpRequest {
     pub fn new() -> Self {
         Self {
-            parser_state: ParserState::Initialized,
             request_line: None,
             headers: Headers::new(),
             body: Vec::new(),
+            body_stream: None,
+            trailers: TrailerSlot::default(),
+            transfer_codings: Vec::new(),
+            params: Vec::new(),
+            peer: None,
+            body_limit: DEFAULT_BODY_LIMIT,
         }
     }
 
@@ -144,134 +189,195 @@ impl HttpRequest {
         self
     }
 
-    pub async fn parse_from<R: AsyncReadExt + Unpin>(conn: &mut R) -> Result<Self> {
-        let mut request = HttpRequest::new();
-        let mut buffer = [0u8; 1024];
-        let mut buffer_len = 0;
-        println!("Starting parse subroutine...");
-        loop {
-
-            // Only read if we need more data
-            if buffer_len == 0 && request.parser_state != ParserState::Done {
-                println!("Buffer Length: {}. Parser Status: {:?}", buffer_len, request.parser_state);
-                println!("Reading from connection...");
-                let n = conn.read(&mut buffer[buffer_len..]).await?;
-                println!("Read {} bytes...", n);
-                buffer_len += n;
+    /// A body to be pulled as it's read, like one still on the connection.
+    pub fn with_body_stream(mut self, body: RequestBody) -> Self {
+        self.body_stream = Some(body);
+        self
+    }
+
+    /// Matches the request path against a pattern like `/users/{id}/posts`,
+    /// capturing `{name}` segments into `params` on success. Literal segments
+    /// match regardless of case; captured values are left as sent.
+    pub fn match_route(&mut self, pattern: &str) -> bool {
+        let Some(rl) = &self.request_line else {
+            return false;
+        };
+        let mut segments = rl.path().trim_end_matches('/').split('/');
+        let mut params = Vec::new();
+        for expected in pattern.trim_end_matches('/').split('/') {
+            let Some(segment) = segments.next() else {
+                return false;
+            };
+            match expected.strip_prefix('{').and_then(|e| e.strip_suffix('}')) {
+                Some(name) if !segment.is_empty() => {
+                    let value = percent_encoding::percent_decode_str(segment).decode_utf8_lossy();
+                    params.push((name.to_string(), value.into_owned()));
+                },
+                Some(_) => return false,
+                None if expected.eq_ignore_ascii_case(segment) => {},
+                None => return false,
             }
+        }
+        if segments.next().is_some() {
+            return false;
+        }
+        self.params = params;
+        true
+    }
 
-            match request.parser_state {
-                ParserState::Initialized => {
-                    println!("Parsing request line...");
-                    let (request_line, consumed) = RequestLine::parse_request_line(&buffer[..buffer_len])?;
-                    request.request_line = request_line;
-                    buffer.copy_within(consumed.., 0);
-                    buffer_len -= consumed;
+    /// Undoes a body's `Content-Encoding`, refusing to take in or produce more
+    /// than `limit` bytes. A body that's still streaming is decoded as it's
+    /// read, so going over the limit fails the read instead.
+    pub fn decode_body(&mut self, limit: usize) -> Result<(), DecodeError> {
+        let Some(content_encoding) = self.headers.get_combined("content-encoding").map(|ce| ce.into_owned()) else {
+            return Ok(());
+        };
+        let codings = compression::parse_content_encoding(&content_encoding)?;
+        self.headers.remove("content-encoding");
+        let Some(mut stream) = self.body_stream.take() else {
+            self.body = compression::decode_content(&content_encoding, std::mem::take(&mut self.body), limit)?;
+            if self.headers.remove("content-length").is_some() {
+                self.headers.insert("content-length", &self.body.len().to_string());
+            }
+            return Ok(());
+        };
+        for coding in codings.into_iter().rev() {
+            stream = RequestBody::new(DecodingReader::new(stream, coding, limit).map_err(DecodeError::Corrupt)?);
+        }
+        self.body_stream = Some(stream);
+        // there's no telling how long the decoded body is until it's been read
+        self.headers.remove("content-length");
+        Ok(())
+    }
 
-                    println!("Consumed {} bytes, RL: {:?}", consumed, request.request_line);
+    /// Whether the body still carries transfer codings besides chunked, which
+    /// `decode_transfer_codings` has to undo before anyone looks at it.
+    pub fn has_transfer_codings(&self) -> bool {
+        !self.transfer_codings.is_empty()
+    }
 
-                    if request.request_line.is_some() {
-                        println!("Request line parsing complete. Moving on...");
-                        request.parser_state = ParserState::ParsingHeaders;
-                    }
-                },
-                ParserState::ParsingHeaders => {
-                    println!("Parsing headers...");
-
-                    if let (Some((field_name, field_value)), consumed) = Headers::parse_headers(&buffer[..buffer_len])? {
-                        println!("Consumed {} bytes, {}: {}", consumed, field_name, field_value);
-
-                        // digging into headers' inner to expose entry. Probably not the best way to do this...
-                        let e: &mut String = request.headers.0.entry(field_name.trim().to_lowercase()).or_default();
-                        if e.is_empty() {
-                            *e = field_value.trim().to_string();
-                        } else {
-                            e.push_str(", ");
-                            e.push_str(field_value.trim());
-                        }
-                        buffer.copy_within(consumed.., 0);
-                        buffer_len -= consumed;
-                    }
-
-                    if buffer[..buffer_len].starts_with(b"\r\n") {
-                        buffer.copy_within(2.., 0);
-                        buffer_len -= 2;
-                        println!("Headers parsing complete. Moving on...");
-
-                        request.parser_state = if request.headers.get("transfer-encoding").map(|s| s.as_str()) == Some("chunked") {
-                            ParserState::ParsingBodyChunked
-                        } else if request.headers.get("content-length").is_none() {
-                            println!("No body to parse. Calling it a day...");
-                            ParserState::Done
-                        } else {
-                            ParserState::ParsingBodyFull
-                        };
-                    }
-                },
-                ParserState::ParsingBodyFull => {
-                    println!("Parsing the full body...");
-                    if let Some(content_length) = request.headers.get("content-length") {
-                        let content_length = content_length.parse::<usize>()?;
-                        
-                        // copy from buffer to body
-                        let to_copy = buffer_len.min(content_length - request.body.len());
-                        request.body.extend_from_slice(&buffer[..to_copy]);
-                        buffer.copy_within(to_copy.., 0);
-                        buffer_len -= to_copy;
-
-                        println!("Copied {} bytes to body, total: {}/{}", to_copy, request.body.len(), content_length);
-
-                        if request.body.len() == content_length {
-                            println!("Body parsing complete. Moving on...");
-                            request.parser_state = ParserState::Done;
-                        }
-                    } else {
-                        // no content-length -> no body
-                        println!("No body to parse. Moving on...");
-                        request.parser_state = ParserState::Done;
-                    }
-                    
-                },
-                ParserState::ParsingBodyChunked => {
-                    println!("Parsing chunked body...");
-                    let size_end= buffer[..buffer_len]
-                        .windows(2)
-                        .position(|two_bytes| two_bytes == b"\r\n")
-                        .ok_or_else(|| anyhow::anyhow!("no chunk size found in data buffer"))?;
-
-                    let size_str = String::from_utf8_lossy(&buffer[..size_end]);
-                    let chunk_size = usize::from_str_radix(&size_str, 16)?;
-
-                    if chunk_size == 0 {
-                        // final chunk: "0\r\n\r\n"
-                        buffer.copy_within(size_end+4.., 0);
-                        buffer_len -= size_end + 4;
-                        request.parser_state = ParserState::Done;
-                        continue;
-                    }
-
-                    // check if we have the complete chunk: <size>\r\n<data>\r\n
-                    let chunk_total = size_end + 2 + chunk_size + 2;
-                    if buffer_len < chunk_total {
-                        // don't have full chunk, need to read more data
-                        break;
-                    }
-
-                    // extract chunk data (skip size line + \r\n)
-                    let data_start = size_end + 2;
-                    let data_end = data_start + chunk_size;
-                    request.body.extend_from_slice(&buffer[data_start..data_end]);
-
-                    // shift buffer (skip chunk + \r\n)
-                    buffer.copy_within(data_end+2.., 0);
-                    buffer_len -= data_end+2;
-                },
-                ParserState::Done => break,
+    /// Undoes transfer codings like the `gzip` in `gzip, chunked`, refusing to
+    /// take in or produce more than `limit` bytes. Like `decode_body`, a body
+    /// that's still streaming is decoded as it's read.
+    pub fn decode_transfer_codings(&mut self, limit: usize) -> Result<(), DecodeError> {
+        let codings = std::mem::take(&mut self.transfer_codings);
+        let Some(mut stream) = self.body_stream.take() else {
+            for coding in codings.into_iter().rev() {
+                self.body = compression::decompress(coding, &self.body, limit)?;
             }
+            return Ok(());
+        };
+        for coding in codings.into_iter().rev() {
+            stream = RequestBody::new(DecodingReader::new(stream, coding, limit).map_err(DecodeError::Corrupt)?);
+        }
+        self.body_stream = Some(stream);
+        Ok(())
+    }
+
+    /// Buffers whatever is left of a streamed body into `self.body`.
+    pub async fn read_body(&mut self) -> Result<&[u8]> {
+        if let Some(mut stream) = self.body_stream.take() {
+            stream.read_to_end(&mut self.body).await?;
         }
-        
+        Ok(&self.body)
+    }
+
+    /// Buffers the body like `read_body`, but stops reading once it's longer
+    /// than `limit` bytes and gives `None` instead.
+    pub async fn read_body_up_to(&mut self, limit: usize) -> std::io::Result<Option<&[u8]>> {
+        if let Some(stream) = self.body_stream.take() {
+            let wanted = (limit as u64 + 1).saturating_sub(self.body.len() as u64);
+            stream.take(wanted).read_to_end(&mut self.body).await?;
+        }
+        if self.body.len() > limit {
+            return Ok(None);
+        }
+        Ok(Some(&self.body))
+    }
+
+    /// Hands the unread body over to the caller to pull from at its own pace.
+    /// `None` if the body has already been buffered.
+    pub fn take_body_stream(&mut self) -> Option<RequestBody> {
+        self.body_stream.take()
+    }
+
+    /// The trailer fields that followed a chunked body, once it's been read to
+    /// the end. Fields that aren't allowed in trailers are left out.
+    pub fn trailers(&self) -> Option<Headers> {
+        self.trailers.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Checks the body against its `Content-Digest`, if it has one. A body
+    /// that's still streaming is checked as it's read: the read that reaches
+    /// its end fails with `InvalidData` on a mismatch.
+    pub fn verify_content_digest(&mut self) -> Result<(), DigestError> {
+        let Some(content_digest) = self.headers.get_combined("content-digest").map(|cd| cd.into_owned()) else {
+            return Ok(());
+        };
+        match self.body_stream.take() {
+            Some(stream) => {
+                self.body_stream = Some(RequestBody::new(VerifyingReader::new(stream, &content_digest)?));
+                Ok(())
+            },
+            None => digest::verify(&content_digest, &self.body),
+        }
+    }
+
+    // Folds a parser event into the request. Body events only come from a
+    // full parse; a streamed body is decoded by its reader instead.
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::RequestLine(request_line) => self.request_line = Some(request_line),
+            // kept as sent, repeats included; get_combined joins list-valued ones
+            Event::Header(field) => self.headers.append_raw(field),
+            Event::HeadersComplete { transfer_codings, .. } => self.transfer_codings = transfer_codings,
+            Event::BodyChunk(data) => self.body.extend_from_slice(&data),
+            Event::Trailers(trailers) => *self.trailers.lock().unwrap_or_else(|e| e.into_inner()) = Some(trailers),
+            Event::End => {},
+        }
+    }
+
+    // Feeds `parser` from `conn` until it's done, folding its events into a
+    // request. Also returns the framing it found for the body.
+    async fn drive<R: AsyncReadExt + Unpin>(conn: &mut R, parser: &mut Parser) -> Result<(Self, Framing)> {
+        let mut request = HttpRequest::new();
+        let mut framing = Framing::None;
+        let mut read_buffer = [0u8; READ_BUFFER_SIZE];
+        while !parser.is_done() {
+            let n = conn.read(&mut read_buffer).await?;
+            if n == 0 {
+                if parser.is_head_complete() {
+                    bail!("connection closed before the body was complete");
+                }
+                bail!("connection closed before the request head was complete");
+            }
+            for event in parser.feed(&read_buffer[..n])? {
+                if let Event::HeadersComplete { framing: found, .. } = &event {
+                    framing = *found;
+                }
+                request.apply(event);
+            }
+        }
+        Ok((request, framing))
+    }
+
+    /// Reads a whole request, body included, into memory.
+    pub async fn parse_from<R: AsyncReadExt + Unpin>(conn: &mut R) -> Result<Self> {
+        let (request, _) = Self::drive(conn, &mut Parser::new()).await?;
         Ok(request)
-    } 
+    }
+
+    /// Reads only the request head; the body is left on the connection for
+    /// the handler to pull via `take_body_stream` or `read_body`.
+    pub async fn parse_streaming<R: AsyncReadExt + Send + Sync + Unpin + 'static>(mut conn: R) -> Result<Self> {
+        let mut parser = Parser::head_only();
+        let (mut request, framing) = Self::drive(&mut conn, &mut parser).await?;
+        if framing != Framing::None {
+            let reader = BodyReader::new(conn, parser.into_leftover(), framing).with_trailer_slot(request.trailers.clone());
+            request.body_stream = Some(RequestBody::new(reader));
+        }
+        Ok(request)
+    }
 
 }
 
@@ -306,7 +412,7 @@ mod test {
         ];
 
         for (i, test_line) in test_data.iter().enumerate() {
-            let result = RequestLine::parse_request_line(&test_line);
+            let result = RequestLine::parse_request_line(test_line);
             if [2,3, 5].contains(&i) {
                 assert!(result.is_err());
                 println!("{}: Error {:?}", i+1, result.err());
@@ -326,19 +432,19 @@ mod test {
         let test_data = [
             b"GET / HTTP/1.1\r\nHost: localhost:42069\r\nUser-Agent: curl/7.81.0\r\nAccept: */*\r\n\r\n".to_vec(),
             b"GET / HTTP/1.1\r\nHost localhost:42069\r\n\r\n".to_vec(), // invalid headers (missing ':')
-            b"GET /coffee HTTP/1.1\r\n\r\n\r\n".to_vec(), // empty headers
+            b"GET /coffee HTTP/1.1\r\n\r\n\r\n".to_vec(), // empty headers, so no Host
             b"POST /prime/agen HTTP/1.1\r\nHost: localhost:42069\r\nUser-Agent: curl/7.81.0\r\nAccept: */*\r\nContent-Type: text/plain\r\nContent-Type: application/json\r\n\r\n".to_vec(),
         ];
         let expected = [
             (HttpMethod::Get, "/".to_string(), ("Host", "localhost:42069")),
             (HttpMethod::Get, "/".to_string(), ("","")), // err placeholder
-            (HttpMethod::Get, "/coffee".to_string(), ("","")),
-            (HttpMethod::Post, "/prime/agen".to_string(), ("Content-Type","text/plain, application/json")),
+            (HttpMethod::Get, "/coffee".to_string(), ("","")), // err placeholder
+            (HttpMethod::Post, "/prime/agen".to_string(), ("Content-Type","text/plain")),
         ];
         for (i, test_line) in test_data.iter().enumerate() {
             let mut reader = std::io::Cursor::new(test_line);
             let result = HttpRequest::parse_from(&mut reader).await;
-            if [1].contains(&i) {
+            if [1, 2].contains(&i) {
                 assert!(result.is_err());
                 println!("{}: Error {:?}", i+1, result.err());
             } else {
@@ -348,9 +454,9 @@ mod test {
                 assert_eq!(expected[i].1, rl.target);
                 if i == 0 {
                     let (k,v) = (expected[i].2.0, expected[i].2.1);
-                    assert_eq!(request.headers.get(k).map(|s| s.as_str()), Some(v));
-                } if i == 2 {
-                    assert_eq!(request.headers.len(), 0);
+                    assert_eq!(request.headers.get(k), Some(v));
+                    // names come through in the case they were sent
+                    assert_eq!(request.headers.iter().map(|(name, _)| name).collect::<Vec<_>>(), ["Host", "User-Agent", "Accept"]);
                 }
 
             }
@@ -364,16 +470,16 @@ mod test {
             b"POST /upload HTTP/1.1\r\nHost: localhost:42069\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n6\r\n World\r\n0\r\n\r\n".to_vec(),
 
             // Single chunk: "Test"
-            b"POST /data HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nTest\r\n0\r\n\r\n".to_vec(),
+            b"POST /data HTTP/1.1\r\nHost: localhost:42069\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nTest\r\n0\r\n\r\n".to_vec(),
 
             // Empty chunked body (just terminator)
-            b"POST /empty HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n".to_vec(),
+            b"POST /empty HTTP/1.1\r\nHost: localhost:42069\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n".to_vec(),
 
             // Three chunks: "abc" + "def" + "ghi" = "abcdefghi"
-            b"POST /multi HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\ndef\r\n3\r\nghi\r\n0\r\n\r\n".to_vec(),
+            b"POST /multi HTTP/1.1\r\nHost: localhost:42069\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\ndef\r\n3\r\nghi\r\n0\r\n\r\n".to_vec(),
 
             // Larger hex chunk size: "10" = 16 bytes in hex
-            b"POST /hex HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n10\r\nSixteenBytesHere\r\n0\r\n\r\n".to_vec(),
+            b"POST /hex HTTP/1.1\r\nHost: localhost:42069\r\nTransfer-Encoding: chunked\r\n\r\n10\r\nSixteenBytesHere\r\n0\r\n\r\n".to_vec(),
         ];
 
         let expected = [
@@ -402,7 +508,7 @@ mod test {
             if i != 2 || !request.body.is_empty() {
                 assert_eq!(
                     request.headers.get("transfer-encoding"),
-                    Some(&"chunked".to_string()),
+                    Some("chunked"),
                     "Test case {} missing chunked header",
                     i
                 );
@@ -410,5 +516,48 @@ mod test {
 
             println!("✓ Test case {}: {} bytes decoded correctly", i, request.body.len());
         }
+
+        let with_trailers = b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nTrailer: X-Checksum\r\n\r\n5;ext=1\r\nHello\r\n0\r\nX-Checksum: 42\r\n\r\n".to_vec();
+        let request = HttpRequest::parse_from(&mut std::io::Cursor::new(with_trailers)).await.unwrap();
+        assert_eq!(request.body, b"Hello");
+        assert_eq!(request.trailers().unwrap().get("x-checksum"), Some("42"));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn ambiguous_framing_is_rejected() {
+        let rejected = [
+            &b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked, chunked\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: Chunked\r\n\r\n0\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nX-Folded: one\r\n two\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost : a\r\n\r\n",
+            b"GET / HTTP/1.1\r\n Host: a\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\nX-Smuggled: b\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n",
+            b"GET / HTTP/1.1\r\nUser-Agent: curl\r\n\r\n",
+        ];
+        for (i, data) in rejected.iter().enumerate() {
+            let result = HttpRequest::parse_from(&mut std::io::Cursor::new(data.to_vec())).await;
+            // Chunked isn't case-sensitive, so that one's fine
+            assert_eq!(result.is_err(), i != 4, "case {}: {:?}", i, String::from_utf8_lossy(data));
+        }
+
+        let same_lengths = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello".to_vec();
+        let request = HttpRequest::parse_from(&mut std::io::Cursor::new(same_lengths)).await.unwrap();
+        assert_eq!(request.body, b"hello");
+
+        let mut gzipped = Vec::new();
+        let mut encoder = flate2::write::GzEncoder::new(&mut gzipped, flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, b"hello").unwrap();
+        encoder.finish().unwrap();
+        let mut data = format!("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip, chunked\r\n\r\n{:x}\r\n", gzipped.len()).into_bytes();
+        data.extend_from_slice(&gzipped);
+        data.extend_from_slice(b"\r\n0\r\n\r\n");
+        let mut request = HttpRequest::parse_from(&mut std::io::Cursor::new(data)).await.unwrap();
+        assert!(request.has_transfer_codings());
+        request.decode_transfer_codings(1024).unwrap();
+        assert_eq!(request.body, b"hello");
+    }
+}
@@ -1,34 +1,66 @@
+use std::borrow::Cow;
 use std::fmt;
-use tokio::net::TcpStream;
-use tokio::io::AsyncWriteExt;
-use sha2::{Sha256, Digest};
+use std::io::IoSlice;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, SeekFrom};
 
+use crate::compression::{self, ContentCoding, Encoder};
+use crate::conditional::{self, EntityTag, Precondition};
+use crate::digest::{self, ContentDigestTrailer, DigestAlgorithm};
 use crate::headers::Headers;
+use crate::request::{HttpMethod, HttpRequest, HttpVersion};
+use crate::sendfile::ZeroCopySocket;
+use crate::trailers::{self, TrailerHook};
+use crate::typed_headers::{ContentLength, Header, parse_quality_list};
 
-#[derive(Debug, Clone, PartialEq, Eq, Copy)]
-pub enum HttpStatus {
-    Ok,
-    BadRequest,
-    InternalServerError,
-}
+pub use crate::status::HttpStatus;
 
-impl fmt::Display for HttpStatus {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            HttpStatus::Ok => write!(f, "HTTP/1.1 200 OK"),
-            HttpStatus::BadRequest => write!(f, "HTTP/1.1 400 Bad Request"),
-            HttpStatus::InternalServerError => write!(f, "HTTP/1.1 500 Internal Server Error"),
-        }
+// responses always go out as HTTP/1.1, whatever the request said
+const RESPONSE_VERSION: HttpVersion = HttpVersion::HTTP11;
+
+// small responses go out in one write; anything bigger is written as it overflows
+const WRITE_BUFFER_SIZE: usize = 8 * 1024;
+// files that can't go out with sendfile are copied through in pieces this big
+const FILE_COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+pub const DEFAULT_SERVER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// The current time as an IMF-fixdate, formatted at most once a second.
+fn http_date_now() -> String {
+    static CACHE: Mutex<Option<(u64, String)>> = Mutex::new(None);
+    let now = SystemTime::now();
+    let second = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    match cache.as_ref() {
+        Some((cached, date)) if *cached == second => date.clone(),
+        _ => {
+            let date = httpdate::fmt_http_date(now);
+            *cache = Some((second, date.clone()));
+            date
+        },
     }
 }
 
-#[derive(Debug)]
+// 1xx, 204 and 304 responses end at the header section, so they get no framing
+fn has_body(status: &HttpStatus) -> bool {
+    !(status.is_informational() || *status == HttpStatus::NoContent || *status == HttpStatus::NotModified)
+}
+
+#[derive(Debug, Clone)]
 pub struct HttpResponse {
     pub status: HttpStatus,
     pub headers: Headers,
     pub body: Vec<u8>,
 }
 
+impl Default for HttpResponse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl HttpResponse {
     pub fn new() -> Self {
         HttpResponse {
@@ -38,17 +70,19 @@ impl HttpResponse {
         }
     }
 
-    pub fn with_status(mut self, status: HttpStatus) -> Self {
-        self.status = status;
+    pub fn with_status(mut self, status: impl Into<HttpStatus>) -> Self {
+        self.status = status.into();
         self
     }
 
+    /// Fills in `Connection` and `Content-Type` where they haven't been set.
+    /// Framing, `Date` and `Server` are left to the writer at send time.
     pub fn with_default_headers(mut self) -> Self {
-        let mut headers = Headers::new();
-        headers.insert("Content-Length", &self.body.len().to_string());
-        headers.insert("Connection", "close");
-        headers.insert("Content-Type", "text/plain");
-        self.headers = headers;
+        for (name, value) in [("Connection", "close"), ("Content-Type", "text/plain")] {
+            if !self.headers.contains(name) {
+                self.headers.insert(name, value);
+            }
+        }
         self
     }
 
@@ -57,17 +91,191 @@ impl HttpResponse {
         self
     }
 
+    pub fn with_typed_header<H: Header>(mut self, header: &H) -> Self {
+        self.headers.insert_typed(header);
+        self
+    }
+
+    /// Adds another field named `name`, keeping any already set, e.g. one
+    /// `Set-Cookie` per cookie.
+    pub fn with_appended_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
     pub fn with_body(mut self, message: &str) -> Self {
         self.body = message.as_bytes().to_vec();
         self
     }
 
+    pub fn with_body_bytes(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Tags a successful response with a strong `ETag` derived from its body,
+    /// unless the handler already supplied one.
+    pub fn with_etag(mut self) -> Self {
+        if self.status == HttpStatus::Ok && self.headers.get("ETag").is_none() {
+            let etag = EntityTag::from_body(&self.body);
+            self.headers.insert("ETag", &etag.to_string());
+        }
+        self
+    }
+
+    /// Whether the body is a candidate for a content-coding at all, regardless
+    /// of what the client accepts.
+    pub fn is_compressible(&self) -> bool {
+        !matches!(self.status, HttpStatus::NotModified)
+            && self.body.len() >= compression::MIN_COMPRESS_SIZE
+            && self.headers.get("Content-Encoding").is_none()
+            && self.headers.get("Content-Type").is_some_and(compression::is_compressible)
+    }
+
+    /// Encodes the body with `coding` and fixes up the headers describing it.
+    pub fn with_content_coding(mut self, coding: ContentCoding) -> Result<Self, std::io::Error> {
+        if coding == ContentCoding::Identity {
+            return Ok(self);
+        }
+        self.body = compression::compress(coding, &self.body)?;
+        mark_encoded(&mut self.headers, coding);
+        self.headers.insert("Content-Length", &self.body.len().to_string());
+        Ok(self)
+    }
+
+    /// Swaps the response for a 304 or 412 when the request's preconditions
+    /// say the client shouldn't get the body.
+    pub fn evaluate_preconditions(self, method: HttpMethod, request_headers: &Headers) -> Self {
+        if self.status != HttpStatus::Ok {
+            return self;
+        }
+        let etag = self.headers.get("ETag").and_then(|v| EntityTag::parse(v).ok());
+        let last_modified = self.headers.get("Last-Modified").and_then(|v| httpdate::parse_http_date(v).ok());
+        let status = match conditional::evaluate(method, request_headers, etag.as_ref(), last_modified) {
+            Precondition::Proceed => return self,
+            Precondition::NotModified => HttpStatus::NotModified,
+            Precondition::Failed => HttpStatus::PreconditionFailed,
+        };
+
+        // a 304 carries the validators and caching metadata of the 200 it stands for
+        let mut headers = Headers::new();
+        if status == HttpStatus::NotModified {
+            for name in ["Cache-Control", "Content-Location", "Date", "ETag", "Expires", "Last-Modified", "Vary"] {
+                if let Some(value) = self.headers.get(name) {
+                    headers.insert(name, value);
+                }
+            }
+        } else {
+            headers.insert("Content-Length", "0");
+        }
+        if let Some(value) = self.headers.get("Connection") {
+            headers.insert("Connection", value);
+        }
+        HttpResponse { status, headers, body: Vec::new() }
+    }
+
 }
 
 impl fmt::Display for HttpResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // unwrap on request line is a little janky, but should never fail at this point
-        write!(f,"{}\r\n{}\r\n{}", self.status, self.headers, String::from_utf8_lossy(&self.body))
+        write!(f,"{} {}\r\n{}\r\n{}", RESPONSE_VERSION, self.status, self.headers, String::from_utf8_lossy(&self.body))
+    }
+}
+
+/// Anything a handler can hand back to be written out as the whole response.
+pub trait IntoResponse {
+    fn into_response(self) -> HttpResponse;
+}
+
+fn body_response(body: Vec<u8>, content_type: &str) -> HttpResponse {
+    HttpResponse::new()
+        .with_body_bytes(body)
+        .with_default_headers()
+        .with_header("Content-Type", content_type)
+}
+
+impl IntoResponse for HttpResponse {
+    fn into_response(self) -> HttpResponse {
+        self
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> HttpResponse {
+        body_response(self.into_bytes(), "text/plain; charset=utf-8")
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> HttpResponse {
+        body_response(self.as_bytes().to_vec(), "text/plain; charset=utf-8")
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> HttpResponse {
+        body_response(self, "application/octet-stream")
+    }
+}
+
+impl IntoResponse for () {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::new().with_default_headers()
+    }
+}
+
+/// An HTML body.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Html<T>(pub T);
+
+impl<T: Into<Vec<u8>>> IntoResponse for Html<T> {
+    fn into_response(self) -> HttpResponse {
+        body_response(self.0.into(), "text/html; charset=utf-8")
+    }
+}
+
+// a bare status says what it is in the body
+impl IntoResponse for HttpStatus {
+    fn into_response(self) -> HttpResponse {
+        let reason = self.reason().to_string();
+        (self, reason).into_response()
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (HttpStatus, T) {
+    fn into_response(self) -> HttpResponse {
+        self.1.into_response().with_status(self.0)
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Ok(response) => response.into_response(),
+            Err(e) => e.into_response(),
+        }
+    }
+}
+
+fn vary_on_accept_encoding(headers: &mut Headers) {
+    let existing = headers.get_combined("Vary").map(|vary| vary.into_owned());
+    let vary = match existing {
+        Some(vary) if vary.split(',').any(|v| v.trim().eq_ignore_ascii_case("accept-encoding") || v.trim() == "*") => vary,
+        Some(vary) => format!("{}, Accept-Encoding", vary),
+        None => "Accept-Encoding".to_string(),
+    };
+    headers.insert("Vary", &vary);
+}
+
+fn mark_encoded(headers: &mut Headers, coding: ContentCoding) {
+    headers.remove("Content-Length");
+    headers.insert("Content-Encoding", coding.token());
+    // a strong validator has to differ between encodings of the same resource
+    if let Some(etag) = headers.get("ETag").and_then(|v| EntityTag::parse(v).ok())
+        && !etag.weak {
+        let encoded = EntityTag::strong(&format!("{}-{}", etag.tag, coding.token()));
+        headers.insert("ETag", &encoded.to_string());
     }
 }
 
@@ -81,69 +289,716 @@ enum WriterState {
     Done,
 }
 
-#[derive(Debug)]
-pub struct ResponseWriter {
-    writer: TcpStream,
+/// Any transport a response can go out over, boxed so handlers don't need
+/// to know which one they're writing to.
+pub type DynWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Writes a response out in the order HTTP needs it, status line first. Output
+/// is buffered: the head is held back to go out with the start of the body,
+/// and everything is flushed once the response is done. Handlers streaming a
+/// body a piece at a time can push what they have so far with `flush`.
+pub struct ResponseWriter<W = DynWriter> {
+    writer: W,
+    buffer: Vec<u8>,
     state: WriterState,
+    request: Option<(HttpMethod, Headers)>,
+    encoder: Option<Encoder>,
+    status: Option<HttpStatus>,
+    server: Option<String>,
+    // body bytes still owed to a Content-Length framed response
+    remaining: Option<u64>,
+    trailer_hooks: Vec<Box<dyn TrailerHook>>,
+    announced_trailers: Vec<String>,
+    content_digest: Option<DigestAlgorithm>,
+    zero_copy: Option<ZeroCopySocket>,
 }
 
 impl ResponseWriter {
-    pub fn from(writer: TcpStream) -> Self {
-        Self { writer, state: WriterState::Initial }
+    pub fn boxed(writer: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+        ResponseWriter::from(Box::new(writer) as DynWriter)
+    }
+}
+
+impl<W> fmt::Debug for ResponseWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseWriter")
+            .field("state", &self.state)
+            .field("buffered", &self.buffer.len())
+            .field("status", &self.status)
+            .field("encoder", &self.encoder)
+            .field("announced_trailers", &self.announced_trailers)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> ResponseWriter<W> {
+    pub fn from(writer: W) -> Self {
+        Self {
+            writer,
+            buffer: Vec::with_capacity(WRITE_BUFFER_SIZE),
+            state: WriterState::Initial,
+            request: None,
+            encoder: None,
+            status: None,
+            server: Some(DEFAULT_SERVER.to_string()),
+            remaining: None,
+            trailer_hooks: Vec::new(),
+            announced_trailers: Vec::new(),
+            content_digest: None,
+            zero_copy: None,
+        }
+    }
+
+    /// Sets the `Server` header added to responses that don't have one;
+    /// `None` leaves it off.
+    pub fn with_server_header(mut self, server: Option<String>) -> Self {
+        self.server = server;
+        self
+    }
+
+    /// Lets `write_file` hand file data straight to `socket`, which has to be
+    /// the plain TCP connection this writer's output ends up on.
+    pub fn with_zero_copy(mut self, socket: Option<ZeroCopySocket>) -> Self {
+        self.zero_copy = socket;
+        self
+    }
+
+    /// Remembers what the client asked for so responses can be validated
+    /// against its conditional headers and compressed per `Accept-Encoding`.
+    pub fn with_request(mut self, req: &HttpRequest) -> Self {
+        self.request = req.request_line.as_ref().map(|rl| (rl.method, req.headers.clone()));
+        self
+    }
+
+    // refuses a write the response isn't ready for, so it can't corrupt what's already been sent
+    fn expect_state(&self, allowed: &[WriterState], action: &str) -> Result<(), std::io::Error> {
+        if allowed.contains(&self.state) {
+            return Ok(());
+        }
+        Err(std::io::Error::other(format!("cannot {} while in state {:?}", action, self.state)))
+    }
+
+    /// Whether anything of the final response has been written yet.
+    pub fn has_started(&self) -> bool {
+        self.state != WriterState::Initial
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == WriterState::Done
+    }
+
+    /// Brings a response the handler left unfinished to a valid end: an error
+    /// response if nothing was written, otherwise whatever closes the framing
+    /// that's open. A `Content-Length` body that came up short can't be saved.
+    pub async fn finish(&mut self, unsent: impl IntoResponse) -> Result<(), std::io::Error> {
+        match self.state {
+            WriterState::Initial => self.write_all(unsent.into_response()).await,
+            WriterState::WritingHeaders => {
+                let mut headers = Headers::new();
+                headers.insert("Content-Length", "0");
+                self.write_headers(&headers).await?;
+                self.write_body_done().await
+            },
+            WriterState::WritingBodyFull | WriterState::WritingBodyChunked => self.write_body_done().await,
+            WriterState::WritingTrailers => {
+                self.send(&[b"\r\n"]).await?;
+                self.done().await
+            },
+            WriterState::Done => Ok(()),
+        }
+    }
+
+    /// Sends everything buffered so far on to the client.
+    pub async fn flush(&mut self) -> Result<(), std::io::Error> {
+        if !self.buffer.is_empty() {
+            self.writer.write_all(&self.buffer).await?;
+            self.buffer.clear();
+        }
+        self.writer.flush().await
+    }
+
+    // Queues `parts` behind what's buffered. Once that would overflow the
+    // buffer, the lot goes out in a single vectored write instead.
+    async fn send(&mut self, parts: &[&[u8]]) -> Result<(), std::io::Error> {
+        let length: usize = parts.iter().map(|part| part.len()).sum();
+        if self.buffer.len() + length <= WRITE_BUFFER_SIZE {
+            for part in parts {
+                self.buffer.extend_from_slice(part);
+            }
+            return Ok(());
+        }
+        let mut slices: Vec<IoSlice<'_>> = std::iter::once(&self.buffer[..])
+            .chain(parts.iter().copied())
+            .filter(|part| !part.is_empty())
+            .map(IoSlice::new)
+            .collect();
+        write_all_vectored(&mut self.writer, &mut slices).await?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    async fn done(&mut self) -> Result<(), std::io::Error> {
+        self.state = WriterState::Done;
+        self.flush().await
+    }
+
+    /// Whether the client said it can take trailers, with `TE: trailers`.
+    pub fn trailers_accepted(&self) -> bool {
+        self.request.as_ref()
+            .and_then(|(_, headers)| headers.get_combined("TE"))
+            .is_some_and(|te| parse_quality_list(&te).iter().any(|item| item.value.eq_ignore_ascii_case("trailers")))
     }
 
-    pub async fn write_all(&mut self, response: &HttpResponse) -> Result<(), std::io::Error> {
+    /// Declares a trailer field the handler will pass to `write_trailers`.
+    /// Has to happen before the headers go out so it can be announced.
+    pub fn announce_trailer(&mut self, name: &str) -> Result<(), std::io::Error> {
+        self.expect_state(&[WriterState::Initial, WriterState::WritingHeaders], "announce a trailer")?;
+        if !trailers::is_allowed_trailer(name) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} can't be sent as a trailer", name)));
+        }
+        self.announced_trailers.push(name.to_string());
+        Ok(())
+    }
+
+    /// Adds a hook that computes a trailer from the body as it's written.
+    pub fn add_trailer(&mut self, hook: Box<dyn TrailerHook>) -> Result<(), std::io::Error> {
+        self.announce_trailer(hook.name())?;
+        self.trailer_hooks.push(hook);
+        Ok(())
+    }
+
+    /// Sends `Content-Digest` in `algorithm` unless the request's
+    /// `Want-Content-Digest` picks another. `None` only adds it on request.
+    pub fn with_content_digest(mut self, algorithm: Option<DigestAlgorithm>) -> Self {
+        self.content_digest = algorithm;
+        self
+    }
+
+    fn wanted_digest(&self) -> Option<DigestAlgorithm> {
+        let want = self.request.as_ref().and_then(|(_, headers)| headers.get_combined("Want-Content-Digest"));
+        match want {
+            Some(want) => digest::negotiate_want(&want),
+            None => self.content_digest,
+        }
+    }
+
+    fn accepted_coding(&self) -> Option<ContentCoding> {
+        let (_, headers) = self.request.as_ref()?;
+        Some(compression::negotiate(headers.get_combined("Accept-Encoding").as_deref()))
+    }
+
+    pub async fn write_all(&mut self, mut response: HttpResponse) -> Result<(), std::io::Error> {
+        if let (Some((method, headers)), Some(coding)) = (&self.request, self.accepted_coding()) {
+            // only a safe method's response is a representation of the target; an
+            // unsafe request has its preconditions checked before the handler runs
+            let safe = method.is_safe();
+            if safe {
+                response = response.with_etag();
+            }
+            if response.is_compressible() {
+                vary_on_accept_encoding(&mut response.headers);
+                response = response.with_content_coding(coding)?;
+            }
+            if safe {
+                response = response.evaluate_preconditions(*method, headers);
+            }
+        }
+        // the length has to describe the body as sent, after any encoding
+        if has_body(&response.status) {
+            response.headers.remove("Transfer-Encoding");
+            response.headers.insert("Content-Length", &response.body.len().to_string());
+            if let Some(algorithm) = self.wanted_digest()
+                && !response.headers.contains("Content-Digest") {
+                response.headers.insert("Content-Digest", &digest::content_digest(algorithm, &response.body));
+            }
+        } else if response.status != HttpStatus::NotModified {
+            response.headers.remove("Content-Length");
+        }
         self.write_status(&response.status).await?;
         self.write_headers(&response.headers).await?;
         self.write_body_full(&response.body).await?;
         Ok(())
     }
 
-    pub async fn write_status(&mut self, status_line: &HttpStatus) -> Result<(), std::io::Error> {
-        self.writer.write_all(format!("{}\r\n", status_line).as_bytes()).await?;
+    pub async fn respond(&mut self, response: impl IntoResponse) -> Result<(), std::io::Error> {
+        self.write_all(response.into_response()).await
+    }
+
+    /// Sends an interim 1xx response ahead of the final one. Only allowed
+    /// before `write_status`, and may be repeated.
+    pub async fn write_informational(&mut self, status: &HttpStatus, headers: &Headers) -> Result<(), std::io::Error> {
+        self.expect_state(&[WriterState::Initial], "send an interim response")?;
+        // 101 switches the connection away from HTTP, so it's a final response in all but name
+        if !status.is_informational() || *status == HttpStatus::SwitchingProtocols || !status.is_valid() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("not an interim status: {:?}", status)));
+        }
+        // the client is waiting on this, so it can't sit in the buffer
+        self.send(&[format!("{} {}\r\n{}\r\n", RESPONSE_VERSION, status, headers).as_bytes()]).await?;
+        self.flush().await
+    }
+
+    /// Tells a client waiting on `Expect: 100-continue` to go ahead with the body.
+    pub async fn write_continue(&mut self) -> Result<(), std::io::Error> {
+        self.write_informational(&HttpStatus::Continue, &Headers::new()).await
+    }
+
+    /// Sends `103 Early Hints` so the client can start fetching the `Link`ed
+    /// resources while the final response is still being put together.
+    pub async fn write_early_hints(&mut self, links: &[&str]) -> Result<(), std::io::Error> {
+        let mut headers = Headers::new();
+        headers.insert("Link", &links.join(", "));
+        self.write_informational(&HttpStatus::EarlyHints, &headers).await
+    }
+
+    pub async fn write_status(&mut self, status: &HttpStatus) -> Result<(), std::io::Error> {
+        self.expect_state(&[WriterState::Initial], "write the status line")?;
+        if !status.is_valid() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid status: {:?}", status)));
+        }
+        self.send(&[format!("{} {}\r\n", RESPONSE_VERSION, status).as_bytes()]).await?;
+        self.status = Some(status.clone());
         self.state = WriterState::WritingHeaders;
         Ok(())
     }
 
+    /// Writes the header section, adding `Date` and `Server` if missing. A
+    /// response with neither `Content-Length` nor `Transfer-Encoding` is sent
+    /// chunked. Chunked responses with a compressible `Content-Type` get
+    /// encoded on the fly per the request's `Accept-Encoding`; bodies framed
+    /// by `Content-Length` go out as they are.
     pub async fn write_headers(&mut self, headers: &Headers) -> Result<(), std::io::Error> {
-        self.writer.write_all(format!("{}\r\n",headers).as_bytes()).await?;
-        self.state = match headers.get("Transfer-Encoding").map(|s| s.as_str()) {
-            Some("chunked") => WriterState::WritingBodyChunked,
-            _ => WriterState::WritingBodyFull,
+        self.expect_state(&[WriterState::WritingHeaders], "write headers")?;
+        let mut headers = Cow::Borrowed(headers);
+        if !headers.contains("Date") {
+            headers.to_mut().insert("Date", &http_date_now());
+        }
+        if let Some(server) = &self.server
+            && !headers.contains("Server") {
+            headers.to_mut().insert("Server", server);
+        }
+        let has_body = self.status.as_ref().is_none_or(has_body);
+        if has_body && !headers.contains("Content-Length") && !headers.contains("Transfer-Encoding") {
+            headers.to_mut().insert("Transfer-Encoding", "chunked");
+        }
+        let chunked = has_body && headers.get("Transfer-Encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked"));
+        // a streamed body's digest isn't known until the end, so it has to trail
+        if chunked && self.trailers_accepted()
+            && let Some(algorithm) = self.wanted_digest()
+            && !headers.contains("Content-Digest")
+            && !self.announced_trailers.iter().any(|name| name.eq_ignore_ascii_case("Content-Digest")) {
+            self.add_trailer(Box::new(ContentDigestTrailer::new(algorithm)))?;
+        }
+        // trailers only ride on chunked bodies, and only to clients that asked for them
+        if chunked && self.trailers_accepted() && !self.announced_trailers.is_empty() {
+            headers.to_mut().insert("Trailer", &self.announced_trailers.join(", "));
+        } else {
+            self.announced_trailers.clear();
+            self.trailer_hooks.clear();
+        }
+        let compressible = headers.get("Content-Encoding").is_none()
+            && headers.get("Content-Type").is_some_and(compression::is_compressible);
+        if chunked && compressible && let Some(coding) = self.accepted_coding() {
+            let headers = headers.to_mut();
+            vary_on_accept_encoding(headers);
+            self.encoder = Encoder::new(coding)?;
+            if self.encoder.is_some() {
+                mark_encoded(headers, coding);
+            }
+        }
+
+        self.remaining = match (has_body, chunked) {
+            (false, _) => Some(0),
+            (true, true) => None,
+            (true, false) => match headers.try_typed::<ContentLength>() {
+                Ok(length) => length.map(|ContentLength(length)| length),
+                Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)),
+            },
+        };
+
+        self.send(&[format!("{}\r\n", headers).as_bytes()]).await?;
+        self.state = if chunked {
+            WriterState::WritingBodyChunked
+        } else {
+            WriterState::WritingBodyFull
         };
         Ok(())
     }
 
     pub async fn write_body_full(&mut self, response_body: &[u8]) -> Result<(), std::io::Error> {
-        self.writer.write_all(response_body).await?;
-        self.state = WriterState::Done;
+        self.expect_state(&[WriterState::WritingBodyFull], "write a whole body")?;
+        self.write_body(response_body).await?;
+        self.write_body_done().await
+    }
+
+    /// Writes part of the body, as a chunk if the response is chunked.
+    pub async fn write_body(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        self.expect_state(&[WriterState::WritingBodyFull, WriterState::WritingBodyChunked], "write body data")?;
+        if self.state == WriterState::WritingBodyChunked {
+            if data.is_empty() {
+                return Ok(());
+            }
+            return self.write_chunked_body(data).await;
+        }
+        if let Some(remaining) = self.remaining {
+            if data.len() as u64 > remaining {
+                return Err(std::io::Error::other(format!("body is {} bytes longer than its Content-Length", data.len() as u64 - remaining)));
+            }
+            self.remaining = Some(remaining - data.len() as u64);
+        }
+        self.send(&[data]).await
+    }
+
+    /// Writes `length` bytes of `file` from `offset` as body. A body framed by
+    /// `Content-Length` goes out with `sendfile(2)` when the writer has a
+    /// socket for it; anything else, like a chunked or encoded body, is copied
+    /// through in large reads.
+    pub async fn write_file(&mut self, file: &mut File, offset: u64, length: u64) -> Result<(), std::io::Error> {
+        self.expect_state(&[WriterState::WritingBodyFull, WriterState::WritingBodyChunked], "write body data")?;
+        // checked up front so an overlong file doesn't go out in part first
+        if let Some(remaining) = self.remaining
+            && length > remaining {
+            return Err(std::io::Error::other(format!("body is {} bytes longer than its Content-Length", length - remaining)));
+        }
+        if self.state == WriterState::WritingBodyFull
+            && let Some(socket) = self.zero_copy.take() {
+            let result = self.send_file(&socket, file, offset, length).await;
+            self.zero_copy = Some(socket);
+            match result {
+                Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {},
+                result => return result,
+            }
+        }
+
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut buffer = vec![0u8; FILE_COPY_BUFFER_SIZE.min(length as usize)];
+        let mut remaining = length;
+        while remaining > 0 {
+            let to_read = remaining.min(buffer.len() as u64) as usize;
+            let n = file.read(&mut buffer[..to_read]).await?;
+            if n == 0 {
+                // the file shrank underneath us; the framing is already committed
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file truncated while serving"));
+            }
+            self.write_body(&buffer[..n]).await?;
+            remaining -= n as u64;
+        }
+        Ok(())
+    }
+
+    async fn send_file(&mut self, socket: &ZeroCopySocket, file: &File, offset: u64, length: u64) -> Result<(), std::io::Error> {
+        // whatever's buffered has to reach the socket before the file does
+        self.flush().await?;
+        socket.send_file(file, offset, length).await?;
+        self.remaining = self.remaining.map(|remaining| remaining - length);
         Ok(())
     }
 
+    /// Ends the body. A chunked body gets the trailers its hooks produce.
+    pub async fn write_body_done(&mut self) -> Result<(), std::io::Error> {
+        self.expect_state(&[WriterState::WritingBodyFull, WriterState::WritingBodyChunked], "end the body")?;
+        if self.state == WriterState::WritingBodyChunked {
+            self.write_chunked_body_done().await?;
+            return self.write_trailers(&Headers::new()).await;
+        } else if let Some(remaining) = self.remaining.filter(|remaining| *remaining > 0) {
+            return Err(std::io::Error::other(format!("body ended {} bytes short of its Content-Length", remaining)));
+        }
+        self.done().await
+    }
+
     pub async fn write_chunked_body(&mut self, chunk: &[u8]) -> Result<(), std::io::Error> {
+        self.expect_state(&[WriterState::WritingBodyChunked], "write a chunk")?;
         if chunk.is_empty() {
             return self.write_chunked_body_done().await;
         }
-        self.writer.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
-        self.writer.write_all(chunk).await?;
-        self.writer.write_all(b"\r\n").await?;
-        Ok(())
+        match self.encoder.as_mut() {
+            Some(encoder) => {
+                let encoded = encoder.encode(chunk)?;
+                self.write_chunk(&encoded).await
+            },
+            None => self.write_chunk(chunk).await,
+        }
+    }
+
+    async fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), std::io::Error> {
+        // an empty chunk would read as the end of the body
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        for hook in &mut self.trailer_hooks {
+            hook.update(chunk);
+        }
+        self.send(&[format!("{:x}\r\n", chunk.len()).as_bytes(), chunk, b"\r\n"]).await
     }
 
     pub async fn write_chunked_body_done(&mut self) -> Result<(), std::io::Error> {
-        self.writer.write_all(b"0\r\n").await?;
+        self.expect_state(&[WriterState::WritingBodyChunked], "end a chunked body")?;
+        if let Some(encoder) = self.encoder.take() {
+            let rest = encoder.finish()?;
+            self.write_chunk(&rest).await?;
+        }
+        self.send(&[b"0\r\n"]).await?;
         self.state = WriterState::WritingTrailers;
         Ok(())
     }
 
-    pub async fn write_trailers(&mut self, body: &[u8]) -> Result<(), std::io::Error> {
+    /// Ends a chunked body with `trailers` plus whatever the hooks produced.
+    /// Fields that weren't announced are dropped, as is everything when the
+    /// client didn't send `TE: trailers`.
+    pub async fn write_trailers(&mut self, trailers: &Headers) -> Result<(), std::io::Error> {
+        self.expect_state(&[WriterState::WritingTrailers], "write trailers")?;
+        let mut fields = Headers::new();
+        for (name, value) in trailers {
+            if self.announced_trailers.iter().any(|announced| announced.eq_ignore_ascii_case(name)) {
+                fields.append(name, value);
+            }
+        }
+        for hook in &mut self.trailer_hooks {
+            let value = hook.value();
+            fields.insert(hook.name(), &value);
+        }
+        self.send(&[format!("{}\r\n", fields).as_bytes()]).await?;
+        self.done().await
+    }
+}
+
+// write_all for a vectored write: keeps going until every slice is out
+async fn write_all_vectored<W: AsyncWrite + Unpin>(writer: &mut W, mut slices: &mut [IoSlice<'_>]) -> Result<(), std::io::Error> {
+    while !slices.is_empty() {
+        let n = writer.write_vectored(slices).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut slices, n);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn into_response_impls() {
+        let response = "hi".into_response();
+        assert_eq!(response.status, HttpStatus::Ok);
+        assert_eq!(response.body, b"hi");
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert_eq!(response.headers.get("Connection"), Some("close"));
+
+        let response = (HttpStatus::BadRequest, Html(String::from("<p>no</p>"))).into_response();
+        assert_eq!(response.status, HttpStatus::BadRequest);
+        assert_eq!(response.headers.get("Content-Type"), Some("text/html; charset=utf-8"));
+
+        let response = "hi".into_response()
+            .with_appended_header("Set-Cookie", "a=1")
+            .with_appended_header("Set-Cookie", "b=2");
+        assert_eq!(response.headers.get_all("set-cookie").collect::<Vec<_>>(), ["a=1", "b=2"]);
+
+        let response = vec![0u8, 1, 2].into_response();
+        assert_eq!(response.headers.get("Content-Type"), Some("application/octet-stream"));
+
+        let failed: Result<&'static str, (HttpStatus, &'static str)> = Err((HttpStatus::UnprocessableContent, "nope"));
+        let response = failed.into_response();
+        assert_eq!(response.status, HttpStatus::UnprocessableContent);
+        assert_eq!(response.body, b"nope");
+    }
+
+    #[test]
+    fn default_headers_fill_gaps() {
+        let response = HttpResponse::new()
+            .with_header("Content-Type", "application/json")
+            .with_body("{}")
+            .with_default_headers();
+        assert_eq!(response.headers.get("Content-Type"), Some("application/json"));
+        assert_eq!(response.headers.get("Connection"), Some("close"));
+        assert!(!response.headers.contains("Content-Length"));
+
+        let date = http_date_now();
+        assert!(httpdate::parse_http_date(&date).is_ok());
+        assert!(!has_body(&HttpStatus::NotModified) && has_body(&HttpStatus::NotFound));
+    }
+
+    fn writer_pair() -> (ResponseWriter<tokio::io::DuplexStream>, tokio::io::DuplexStream) {
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        (ResponseWriter::from(server), client)
+    }
+
+    #[tokio::test]
+    async fn misordered_writes_are_refused() {
+        let (mut writer, _client) = writer_pair();
+        assert!(writer.write_headers(&Headers::new()).await.is_err());
+        writer.write_status(&HttpStatus::Ok).await.unwrap();
+        assert!(writer.write_status(&HttpStatus::Ok).await.is_err());
+
         let mut headers = Headers::new();
-        let body_hash = Sha256::digest(body);
-        headers.insert("X-Content-SHA256", &format!("{:x}", body_hash));
-        headers.insert("X-Content-Length", body.len().to_string().as_str());
-        self.writer.write_all(headers.to_string().as_bytes()).await?;
-        self.writer.write_all(b"\r\n").await?;
-        self.state = WriterState::Done;
-        Ok(())
+        headers.insert("Content-Length", "5");
+        writer.write_headers(&headers).await.unwrap();
+        assert!(writer.write_headers(&headers).await.is_err());
+        assert!(writer.write_chunked_body(b"hello").await.is_err());
+        assert!(writer.write_body(b"hello!").await.is_err());
+        writer.write_body(b"hel").await.unwrap();
+        assert!(writer.write_body_done().await.is_err());
+        writer.write_body(b"lo").await.unwrap();
+        writer.write_body_done().await.unwrap();
+        assert!(writer.write_trailers(&Headers::new()).await.is_err());
+        assert!(writer.write_body(b"more").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn interim_responses_come_first() {
+        use tokio::io::AsyncReadExt;
+
+        let (mut writer, mut client) = writer_pair();
+        writer.write_early_hints(&["</style.css>; rel=preload; as=style", "</app.js>; rel=preload; as=script"]).await.unwrap();
+        writer.write_early_hints(&["</font.woff2>; rel=preload; as=font"]).await.unwrap();
+        assert!(writer.write_informational(&HttpStatus::SwitchingProtocols, &Headers::new()).await.is_err());
+        assert!(writer.write_informational(&HttpStatus::Ok, &Headers::new()).await.is_err());
+        writer.write_status(&HttpStatus::Ok).await.unwrap();
+        // once the final status line is out, it's too late for hints
+        assert!(writer.write_early_hints(&["</late.js>; rel=preload"]).await.is_err());
+        assert!(writer.write_continue().await.is_err());
+        writer.finish(HttpStatus::Ok).await.unwrap();
+        drop(writer);
+
+        let mut sent = String::new();
+        client.read_to_string(&mut sent).await.unwrap();
+        assert!(sent.starts_with("HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload; as=style, </app.js>; rel=preload; as=script\r\n\r\n\
+            HTTP/1.1 103 Early Hints\r\nLink: </font.woff2>; rel=preload; as=font\r\n\r\n\
+            HTTP/1.1 200 OK\r\n"), "{}", sent);
+        assert!(!sent.contains("late.js"));
+    }
+
+    #[tokio::test]
+    async fn finish_completes_the_response() {
+        use tokio::io::AsyncReadExt;
+
+        let (mut writer, mut client) = writer_pair();
+        writer.finish(HttpStatus::NotFound).await.unwrap();
+        assert!(writer.is_done());
+        drop(writer);
+        let mut sent = String::new();
+        client.read_to_string(&mut sent).await.unwrap();
+        assert!(sent.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let (mut writer, mut client) = writer_pair();
+        writer.write_status(&HttpStatus::Ok).await.unwrap();
+        writer.write_headers(&Headers::new()).await.unwrap();
+        writer.write_body(b"partial").await.unwrap();
+        writer.finish(HttpStatus::InternalServerError).await.unwrap();
+        drop(writer);
+        let mut sent = String::new();
+        client.read_to_string(&mut sent).await.unwrap();
+        assert!(sent.ends_with("7\r\npartial\r\n0\r\n\r\n"));
+    }
+
+    async fn chunked_with_trailers(te: Option<&str>) -> String {
+        use tokio::io::AsyncReadExt;
+        use crate::request::{RequestLine, HttpVersion};
+        use crate::trailers::LengthTrailer;
+
+        let mut req = HttpRequest::new()
+            .with_request_line(RequestLine { method: HttpMethod::Get, target: "/".to_string(), version: HttpVersion::HTTP11 });
+        if let Some(te) = te {
+            req = req.with_header("te", te);
+        }
+        let (mut writer, mut client) = writer_pair();
+        writer = writer.with_request(&req).with_server_header(None);
+        writer.add_trailer(Box::new(LengthTrailer::new("X-Length"))).unwrap();
+        writer.announce_trailer("X-Note").unwrap();
+        assert!(writer.announce_trailer("Content-Length").is_err());
+        writer.write_status(&HttpStatus::Ok).await.unwrap();
+        writer.write_headers(&Headers::new()).await.unwrap();
+        writer.write_body(b"hello").await.unwrap();
+        writer.write_chunked_body_done().await.unwrap();
+        let mut trailers = Headers::new();
+        trailers.insert("X-Note", "done");
+        trailers.insert("X-Unannounced", "dropped");
+        writer.write_trailers(&trailers).await.unwrap();
+        drop(writer);
+        let mut sent = String::new();
+        client.read_to_string(&mut sent).await.unwrap();
+        sent
+    }
+
+    #[tokio::test]
+    async fn trailers_need_te() {
+        let sent = chunked_with_trailers(Some("trailers")).await;
+        assert!(sent.contains("\r\nTrailer: X-Length, X-Note\r\n"));
+        assert!(sent.ends_with("5\r\nhello\r\n0\r\nX-Note: done\r\nX-Length: 5\r\n\r\n"));
+
+        let sent = chunked_with_trailers(None).await;
+        assert!(!sent.contains("Trailer:"));
+        assert!(sent.ends_with("5\r\nhello\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn buffered_bodies_get_a_content_digest() {
+        use tokio::io::AsyncReadExt;
+
+        let (writer, mut client) = writer_pair();
+        let mut writer = writer.with_content_digest(Some(DigestAlgorithm::Sha256));
+        writer.respond("hello").await.unwrap();
+        drop(writer);
+        let mut sent = String::new();
+        client.read_to_string(&mut sent).await.unwrap();
+        assert!(sent.contains("\r\nContent-Digest: sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:\r\n"));
+    }
+
+    // records the size of every write that reaches the transport
+    #[derive(Default)]
+    struct Writes(Vec<usize>, Vec<u8>);
+
+    impl AsyncWrite for Writes {
+        fn poll_write(mut self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, buf: &[u8]) -> std::task::Poll<std::io::Result<usize>> {
+            self.0.push(buf.len());
+            self.1.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_write_vectored(mut self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, bufs: &[IoSlice<'_>]) -> std::task::Poll<std::io::Result<usize>> {
+            let n = bufs.iter().map(|buf| buf.len()).sum();
+            self.0.push(n);
+            for buf in bufs {
+                self.1.extend_from_slice(buf);
+            }
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn writes_are_coalesced() {
+        let mut writer = ResponseWriter::from(Writes::default());
+        writer.respond("hello").await.unwrap();
+        assert_eq!(writer.writer.0.len(), 1);
+
+        // the head and a big chunk's framing ride along with its data
+        let mut writer = ResponseWriter::from(Writes::default());
+        writer.write_status(&HttpStatus::Ok).await.unwrap();
+        writer.write_headers(&Headers::new()).await.unwrap();
+        writer.write_body(&[b'x'; WRITE_BUFFER_SIZE]).await.unwrap();
+        assert_eq!(writer.writer.0.len(), 1);
+        writer.write_body(b"small").await.unwrap();
+        assert_eq!(writer.writer.0.len(), 1);
+        writer.flush().await.unwrap();
+        assert_eq!(writer.writer.0.len(), 2);
+        writer.write_body_done().await.unwrap();
+        assert_eq!(writer.writer.0.len(), 3);
+        assert!(writer.writer.1.ends_with(b"\r\n5\r\nsmall\r\n0\r\n\r\n"));
+
+        let mut writer = ResponseWriter::from(Writes::default());
+        writer.write_continue().await.unwrap();
+        assert_eq!(writer.writer.1, b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+}
@@ -0,0 +1,169 @@
+use std::io;
+#[cfg(unix)]
+use std::os::fd::{AsFd, OwnedFd};
+
+use tokio::fs::File;
+#[cfg(unix)]
+use tokio::io::{Interest, unix::AsyncFd};
+
+/// A duplicate of a plain TCP connection's descriptor, so file-backed bodies
+/// can be handed to the kernel with `sendfile(2)` instead of being copied
+/// through userspace. Only make one for sockets nothing else has to see the
+/// bytes of on their way out, which rules out TLS.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct ZeroCopySocket(AsyncFd<OwnedFd>);
+
+/// Without descriptors to hand the kernel there's no socket to make, so
+/// file bodies always take the buffered copy.
+#[cfg(not(unix))]
+#[derive(Debug)]
+pub enum ZeroCopySocket {}
+
+#[cfg(not(unix))]
+impl ZeroCopySocket {
+    pub async fn send_file(&self, _file: &File, _offset: u64, _length: u64) -> io::Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(unix)]
+impl ZeroCopySocket {
+    pub fn new(socket: &impl AsFd) -> io::Result<Self> {
+        let socket = socket.as_fd().try_clone_to_owned()?;
+        Ok(Self(AsyncFd::with_interest(socket, Interest::WRITABLE)?))
+    }
+
+    /// Sends `length` bytes of `file` starting at `offset`, leaving the
+    /// file's own position alone. Fails with `Unsupported`, having sent
+    /// nothing, when the kernel can't do it for this pair of descriptors.
+    pub async fn send_file(&self, file: &File, offset: u64, length: u64) -> io::Result<()> {
+        let mut offset = offset;
+        let mut remaining = length;
+        while remaining > 0 {
+            let mut guard = self.0.writable().await?;
+            // a full socket buffer clears the readiness and goes back to waiting
+            let Ok(sent) = guard.try_io(|socket| imp::send_file(socket.as_fd(), file.as_fd(), &mut offset, remaining)) else {
+                continue;
+            };
+            match sent {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated while serving")),
+                Ok(sent) => remaining -= sent as u64,
+                Err(e) if e.kind() == io::ErrorKind::Unsupported && remaining < length => {
+                    return Err(io::Error::other("sendfile stopped partway through"));
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::io;
+    use std::os::fd::{AsRawFd, BorrowedFd};
+
+    // sendfile moves at most this much per call anyway
+    const MAX_SEND: u64 = 0x7fff_f000;
+
+    /// One `sendfile(2)` call, advancing `offset` past what went out. A full
+    /// socket buffer comes back as `WouldBlock`.
+    pub fn send_file(socket: BorrowedFd, file: BorrowedFd, offset: &mut u64, count: u64) -> io::Result<usize> {
+        loop {
+            let mut position = *offset as libc::off_t;
+            // SAFETY: both descriptors are borrowed for the duration of the call,
+            // and `position` is a valid off_t the kernel advances past what it sent
+            let sent = unsafe {
+                libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut position, count.min(MAX_SEND) as usize)
+            };
+            if sent >= 0 {
+                *offset = position as u64;
+                return Ok(sent as usize);
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => {},
+                Some(libc::EINVAL | libc::ENOSYS) => return Err(io::ErrorKind::Unsupported.into()),
+                _ => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+mod imp {
+    use std::io;
+    use std::os::fd::BorrowedFd;
+
+    pub fn send_file(_socket: BorrowedFd, _file: BorrowedFd, _offset: &mut u64, _count: u64) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::headers::Headers;
+    use crate::response::{HttpStatus, ResponseWriter};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn files_go_out_with_or_without_sendfile() {
+        let path = std::env::temp_dir().join(format!("sendfile-test-{}", std::process::id()));
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        for zero_copy in [true, false] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let socket = zero_copy.then(|| ZeroCopySocket::new(&server).unwrap());
+
+            let reader = tokio::spawn(async move {
+                let mut sent = Vec::new();
+                client.read_to_end(&mut sent).await.unwrap();
+                sent
+            });
+            let mut writer = ResponseWriter::boxed(server).with_zero_copy(socket).with_server_header(None);
+            let mut file = File::open(&path).await.unwrap();
+            let mut headers = Headers::new();
+            headers.insert("Date", "now");
+            headers.insert("Content-Length", "150000");
+            writer.write_status(&HttpStatus::Ok).await.unwrap();
+            writer.write_headers(&headers).await.unwrap();
+            assert!(writer.write_file(&mut file, 10, 150_001).await.is_err());
+            writer.write_file(&mut file, 10, 150_000).await.unwrap();
+            writer.write_body_done().await.unwrap();
+            drop(writer);
+
+            let sent = reader.await.unwrap();
+            let head = b"HTTP/1.1 200 OK\r\nDate: now\r\nContent-Length: 150000\r\n\r\n";
+            assert_eq!(&sent[..head.len()], head);
+            assert!(sent[head.len()..] == content[10..150_010], "zero copy: {}", zero_copy);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_stalled_client_leaves_the_transfer_cancellable() {
+        let path = std::env::temp_dir().join(format!("sendfile-stall-test-{}", std::process::id()));
+        std::fs::write(&path, vec![7u8; 16 * 1024 * 1024]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let socket = ZeroCopySocket::new(&server).unwrap();
+        let file = File::open(&path).await.unwrap();
+
+        // the client reads nothing, so this waits on the socket until dropped
+        let send = socket.send_file(&file, 0, 16 * 1024 * 1024);
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(200), send).await.is_err());
+        drop((socket, server));
+
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).await.unwrap();
+        assert!(!sent.is_empty() && sent.len() < 16 * 1024 * 1024);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
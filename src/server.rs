@@ -1,56 +1,465 @@
+use std::time::SystemTime;
+
 use anyhow::Result;
-use tokio::net::{TcpListener, TcpStream};
-use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::oneshot;
+use tokio::task::JoinSet;
+use tokio::time::Interval;
+
+// transfer codings have to be undone whether or not decompression is enabled
+const DEFAULT_MAX_DECODED_BODY: usize = 16 * 1024 * 1024;
+
+use crate::{request::{DEFAULT_BODY_LIMIT, HttpRequest}, response::{DEFAULT_SERVER, HttpStatus, ResponseWriter}};
+use crate::conditional::{self, EntityTag, Precondition};
+use crate::digest::DigestAlgorithm;
+use crate::listener::{Bind, Connection, Listener, PeerInfo};
+use crate::sendfile::ZeroCopySocket;
+#[cfg(unix)]
+use crate::systemd::Notifier;
+use crate::handlers::{dispatch_handler, HandlerError};
+
+/// Decides whether a request sent with `Expect: 100-continue` gets its body
+/// read, from the head alone. An error is sent as the final response instead.
+pub type ExpectCheck = fn(&HttpRequest) -> Result<(), HandlerError>;
 
-use crate::{request::HttpRequest, response::ResponseWriter};
-use crate::handlers::dispatch_handler;
+/// Looks up the validators the request's target has right now, so an unsafe
+/// request's preconditions can be checked before its handler runs. Both are
+/// `None` for a target that doesn't exist.
+pub type ResourceValidators = fn(&HttpRequest) -> (Option<EntityTag>, Option<SystemTime>);
+
+/// Settings every connection is handled with.
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    pub max_decoded_body: Option<usize>,
+    pub body_limit: usize,
+    pub expect_check: Option<ExpectCheck>,
+    pub resource_validators: Option<ResourceValidators>,
+    pub server_header: Option<String>,
+    pub content_digest: Option<DigestAlgorithm>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            max_decoded_body: None,
+            body_limit: DEFAULT_BODY_LIMIT,
+            expect_check: None,
+            resource_validators: None,
+            server_header: Some(DEFAULT_SERVER.to_string()),
+            content_digest: None,
+        }
+    }
+}
 
 pub struct HttpServer {
-    listener: TcpListener,
+    listeners: Vec<Listener>,
     close_conn_rx: oneshot::Receiver<()>,
+    config: ConnectionConfig,
+    notifier: Option<Notifier>,
 }
 
 impl HttpServer {
     pub async fn serve(port: usize) -> Result<(Self, oneshot::Sender<()>)> {
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
+        Self::bind(&[Bind::Tcp(format!("0.0.0.0:{}", port).parse()?)]).await
+    }
+
+    /// Listens on every address in `binds` at once, all served the same way.
+    pub async fn bind(binds: &[Bind]) -> Result<(Self, oneshot::Sender<()>)> {
+        let mut listeners = Vec::new();
+        for bind in binds {
+            let listener = Listener::bind(bind).await
+                .map_err(|e| anyhow::anyhow!("couldn't listen on {}: {}", bind, e))?;
+            listeners.push(listener);
+        }
+        Ok(Self::from_listeners(listeners))
+    }
+
+    /// Serves sockets that are already listening, like the ones systemd
+    /// passes in with socket activation.
+    pub fn from_listeners(listeners: Vec<Listener>) -> (Self, oneshot::Sender<()>) {
         let (tx, rx) = oneshot::channel::<()>();
-        Ok((Self {
-            listener,
+        (Self {
+            listeners,
             close_conn_rx: rx,
-        }, tx))
+            config: ConnectionConfig::default(),
+            notifier: None,
+        }, tx)
+    }
+
+    /// Tells systemd when the server is ready and when it's stopping, and
+    /// feeds its watchdog while running.
+    #[cfg(unix)]
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Where the server's listening, as `host:port` or `unix:path`.
+    pub fn local_addrs(&self) -> Vec<String> {
+        self.listeners.iter().filter_map(|listener| listener.local_addr().ok()).collect()
+    }
+
+    /// Decodes `Content-Encoding` request bodies before handlers see them,
+    /// rejecting any that would inflate past `limit` bytes.
+    pub fn with_request_decompression(mut self, limit: usize) -> Self {
+        self.config.max_decoded_body = Some(limit);
+        self
+    }
+
+    /// Caps how much of a body extractors like `Json` and `Form` will buffer;
+    /// anything longer is answered with a 413.
+    pub fn with_body_limit(mut self, limit: usize) -> Self {
+        self.config.body_limit = limit;
+        self
+    }
+
+    /// Runs `check` on requests that wait for `100 Continue` before sending
+    /// their body. Without one, every such request is told to continue.
+    pub fn with_expect_check(mut self, check: ExpectCheck) -> Self {
+        self.config.expect_check = Some(check);
+        self
+    }
+
+    /// Checks `If-Match` and friends on POSTs against what `lookup` says the
+    /// target looks like. Without one, the target is taken not to exist.
+    pub fn with_resource_validators(mut self, lookup: ResourceValidators) -> Self {
+        self.config.resource_validators = Some(lookup);
+        self
+    }
+
+    /// Sets the `Server` header sent on every response; `None` leaves it off.
+    pub fn with_server_header(mut self, server: Option<&str>) -> Self {
+        self.config.server_header = server.map(str::to_string);
+        self
+    }
+
+    /// Adds `Content-Digest` to responses in `algorithm` when the client
+    /// doesn't say which it wants with `Want-Content-Digest`.
+    pub fn with_content_digest(mut self, algorithm: DigestAlgorithm) -> Self {
+        self.config.content_digest = Some(algorithm);
+        self
     }
 
     pub async fn listen(&mut self) -> Result<()> {
-        loop {
+        // each listener gets its own accept loop; the first to fail takes the server down
+        let mut accept_loops = JoinSet::new();
+        for listener in std::mem::take(&mut self.listeners) {
+            accept_loops.spawn(Self::accept_loop(listener, self.config.clone()));
+        }
+        let notifier = &self.notifier;
+        notify(notifier, Notifier::ready);
+        // pinged twice per interval, as systemd recommends
+        let mut watchdog = notifier.as_ref()
+            .and_then(Notifier::watchdog_interval)
+            .map(|interval| tokio::time::interval(interval / 2));
+
+        let result = loop {
             tokio::select! {
-                _ = &mut self.close_conn_rx => break,
-                result = self.listener.accept() => {
-                    let (conn, addr) = result?;
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::handle_connection(conn, addr).await {
-                            eprintln!("Connection error from {}: {}", addr, e);
-                        }
-                    });
+                _ = &mut self.close_conn_rx => break Ok(()),
+                Some(result) = accept_loops.join_next() => break match result {
+                    Ok(result) => result.map_err(anyhow::Error::from),
+                    Err(e) => Err(e.into()),
+                },
+                _ = tick(&mut watchdog) => notify(notifier, Notifier::watchdog),
+            }
+        };
+        notify(notifier, Notifier::stopping);
+        accept_loops.shutdown().await;
+        println!("Gracefully shutting down server...");
+        result
+    }
+
+    async fn accept_loop(listener: Listener, config: ConnectionConfig) -> std::io::Result<()> {
+        loop {
+            let (conn, peer) = listener.accept().await?;
+            let config = config.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::handle_connection(conn, peer.clone(), config).await {
+                    eprintln!("Connection error from {}: {}", peer, e);
                 }
+            });
+        }
+    }
+
+    pub async fn handle_connection(conn: Connection, peer: PeerInfo, config: ConnectionConfig) -> Result<()> {
+        println!("Accepted connection from: {}", peer);
+        match conn {
+            Connection::Tcp(conn) => {
+                // a plain TCP connection, so file bodies can skip userspace on the way out
+                #[cfg(unix)]
+                let zero_copy = ZeroCopySocket::new(&conn).ok();
+                #[cfg(not(unix))]
+                let zero_copy = None;
+                let (read_half, write_half) = conn.into_split();
+                Self::serve_request(read_half, write_half, peer, zero_copy, config).await
+            },
+            #[cfg(unix)]
+            Connection::Unix(conn) => {
+                let (read_half, write_half) = conn.into_split();
+                Self::serve_request(read_half, write_half, peer, None, config).await
+            },
+        }
+    }
+
+    async fn serve_request<R, W>(read_half: R, write_half: W, peer: PeerInfo, zero_copy: Option<ZeroCopySocket>, config: ConnectionConfig) -> Result<()>
+    where
+        R: AsyncRead + Send + Sync + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let mut request = match HttpRequest::parse_streaming(read_half).await {
+            Ok(request) => request,
+            Err(e) => {
+                // malformed or ambiguously framed; answer and hang up without reading further
+                let error = HandlerError { status_code: HttpStatus::BadRequest, message: e.to_string() };
+                ResponseWriter::boxed(write_half)
+                    .with_server_header(config.server_header)
+                    .write_all(error.to_response()).await?;
+                return Ok(());
+            },
+        };
+        request.peer = Some(peer.clone());
+        request.body_limit = config.body_limit;
+
+        let mut writer = ResponseWriter::boxed(write_half)
+            .with_request(&request)
+            .with_server_header(config.server_header)
+            .with_content_digest(config.content_digest)
+            .with_zero_copy(zero_copy);
+
+        // an unsafe request's preconditions are about the target as it stands, so
+        // they're settled before the handler gets the chance to change it
+        if let Some(method) = request.request_line.as_ref().map(|rl| rl.method)
+            && !method.is_safe()
+            && conditional::has_preconditions(&request.headers) {
+            let (etag, last_modified) = config.resource_validators.map_or((None, None), |lookup| lookup(&request));
+            if conditional::evaluate(method, &request.headers, etag.as_ref(), last_modified) != Precondition::Proceed {
+                let error = HandlerError { status_code: HttpStatus::PreconditionFailed, message: "precondition failed".to_string() };
+                writer.write_all(error.to_response()).await?;
+                return Ok(());
+            }
+        }
+
+        // answer before touching the body; a rejected request never has it read
+        if let Some(expect) = request.headers.get("expect") {
+            let verdict = if !expect.eq_ignore_ascii_case("100-continue") {
+                Err(HandlerError { status_code: HttpStatus::ExpectationFailed, message: format!("unsupported expectation: {}", expect) })
+            } else {
+                config.expect_check.map_or(Ok(()), |check| check(&request))
             };
+            match verdict {
+                Ok(()) => writer.write_continue().await?,
+                Err(e) => {
+                    writer.write_all(e.to_response()).await?;
+                    return Ok(());
+                },
+            }
         }
-        println!("Gracefully shutting down server...");
+
+        // The body comes apart in layers as the handler reads it. Transfer codings
+        // are only how it travelled, so they come off first; the digest covers
+        // the content with any Content-Encoding still on.
+        if request.has_transfer_codings()
+            && let Err(e) = request.decode_transfer_codings(config.max_decoded_body.unwrap_or(DEFAULT_MAX_DECODED_BODY)) {
+            writer.write_all(HandlerError::from(e).to_response()).await?;
+            return Ok(());
+        }
+        if let Err(e) = request.verify_content_digest() {
+            writer.write_all(HandlerError::from(e).to_response()).await?;
+            return Ok(());
+        }
+        // only a coding we can't undo is caught here; the rest fails the read
+        if let Some(limit) = config.max_decoded_body
+            && let Err(e) = request.decode_body(limit) {
+            writer.write_all(HandlerError::from(e).to_response()).await?;
+            return Ok(());
+        }
+
+        // Call handler, then make sure the client ends up with a whole response
+        // however it returned. Once bytes are out an error can only be logged.
+        let result = dispatch_handler(&mut writer, &mut request).await;
+        if let Err(e) = &result && writer.has_started() {
+            eprintln!("Handler failed mid-response for {}: {}", peer, e.message);
+        }
+        if !writer.is_done() {
+            let unsent = result.err().unwrap_or_else(|| HandlerError {
+                status_code: HttpStatus::InternalServerError,
+                message: "handler returned without responding".to_string(),
+            });
+            writer.finish(unsent).await?;
+        }
+
+        println!("Terminating connection from: {}", peer);
         Ok(())
     }
+}
+
+// there's no systemd off Unix, so there's never a notifier to call
+#[cfg(not(unix))]
+enum Notifier {}
+
+#[cfg(not(unix))]
+impl Notifier {
+    fn ready(&self) -> std::io::Result<()> {
+        match *self {}
+    }
 
-    pub async fn handle_connection(mut conn: TcpStream, addr: SocketAddr) -> Result<()> {
-        println!("Accepted connection from: {}", addr);
-        let request = HttpRequest::parse_from(&mut conn).await?;
+    fn stopping(&self) -> std::io::Result<()> {
+        match *self {}
+    }
+
+    fn watchdog(&self) -> std::io::Result<()> {
+        match *self {}
+    }
+
+    fn watchdog_interval(&self) -> Option<std::time::Duration> {
+        match *self {}
+    }
+}
+
+// a server that can't reach systemd keeps serving; the unit's timeouts will complain
+fn notify(notifier: &Option<Notifier>, send: fn(&Notifier) -> std::io::Result<()>) {
+    if let Some(notifier) = notifier
+        && let Err(e) = send(notifier) {
+        eprintln!("Couldn't notify systemd: {}", e);
+    }
+}
+
+// never completes when there's no watchdog to feed
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => { interval.tick().await; },
+        None => std::future::pending().await,
+    }
+}
 
-        let mut writer = ResponseWriter::from(conn);
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression::{self, ContentCoding};
+    use crate::digest;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
 
-        // Call handler
-        if let Err(e) = dispatch_handler(&mut writer, &request).await {
-            writer.write_all(&e.to_response()).await?;
+    // A client on one end of an in-memory connection, with the server handling
+    // the other. The client never hangs up, so a server reading a body that
+    // wasn't sent stalls instead of seeing the end of it.
+    fn connect(config: ConnectionConfig) -> (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>, tokio::task::JoinHandle<Result<()>>) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (read_half, write_half) = tokio::io::split(server);
+        let peer = PeerInfo::Tcp("127.0.0.1:1".parse().unwrap());
+        let handle = tokio::spawn(HttpServer::serve_request(read_half, write_half, peer, None, config));
+        let (client_read, client_write) = tokio::io::split(client);
+        (client_read, client_write, handle)
+    }
+
+    // sends `request` and waits for the server to be done with it
+    async fn exchange(config: ConnectionConfig, request: &[u8]) -> String {
+        let (mut client_read, mut client_write, handle) = connect(config);
+        client_write.write_all(request).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), handle).await
+            .expect("server waited on a body it shouldn't read")
+            .unwrap().unwrap();
+        let mut sent = String::new();
+        client_read.read_to_string(&mut sent).await.unwrap();
+        sent
+    }
+
+    #[tokio::test]
+    async fn unsafe_preconditions_come_before_the_handler() {
+        let config = ConnectionConfig {
+            resource_validators: Some(|_| (Some(EntityTag::strong("v2")), None)),
+            ..ConnectionConfig::default()
         };
+        // the body is never sent, so the upload handler would stall
+        let stale = b"POST /upload HTTP/1.1\r\nHost: a\r\nIf-Match: \"v1\"\r\nContent-Length: 5\r\n\r\n";
+        let sent = exchange(config.clone(), stale).await;
+        assert!(sent.starts_with("HTTP/1.1 412 Precondition Failed\r\n"), "{}", sent);
 
-        println!("Terminating connection from: {}", addr);
-        Ok(())
+        let current = b"POST /upload HTTP/1.1\r\nHost: a\r\nIf-Match: \"v2\"\r\nContent-Length: 5\r\n\r\nhello";
+        let sent = exchange(config, current).await;
+        assert!(sent.starts_with("HTTP/1.1 200 OK\r\n"), "{}", sent);
+        // a POST's response isn't the target, so it gets no ETag of its own
+        assert!(!sent.contains("ETag"), "{}", sent);
+
+        // with nothing to look the target up in, it doesn't exist
+        let sent = exchange(ConnectionConfig::default(), stale).await;
+        assert!(sent.starts_with("HTTP/1.1 412 Precondition Failed\r\n"), "{}", sent);
+    }
+
+    #[tokio::test]
+    async fn continue_comes_before_the_body() {
+        let (mut client_read, mut client_write, handle) = connect(ConnectionConfig::default());
+        client_write.write_all(b"POST /upload HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n").await.unwrap();
+        // nothing else has been sent, so the server has to answer from the head alone
+        let mut interim = [0u8; 25];
+        tokio::time::timeout(Duration::from_secs(5), client_read.read_exact(&mut interim)).await.unwrap().unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        client_write.write_all(b"hello").await.unwrap();
+        handle.await.unwrap().unwrap();
+        let mut sent = String::new();
+        client_read.read_to_string(&mut sent).await.unwrap();
+        assert!(sent.starts_with("HTTP/1.1 200 OK\r\n"), "{}", sent);
+        assert!(sent.contains("received 5 bytes"), "{}", sent);
+    }
+
+    #[tokio::test]
+    async fn rejected_expectations_leave_the_body_unread() {
+        // exchange times out if the server waits on the body that never comes
+        let sent = exchange(ConnectionConfig::default(), b"POST /upload HTTP/1.1\r\nHost: a\r\nExpect: 200-ok\r\nContent-Length: 5\r\n\r\n").await;
+        assert!(sent.starts_with("HTTP/1.1 417 Expectation Failed\r\n"), "{}", sent);
+        assert!(!sent.contains("100 Continue"));
+
+        let config = ConnectionConfig { expect_check: Some(crate::handlers::check_expectation), ..ConnectionConfig::default() };
+        let huge = b"POST /upload HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 4294967296\r\n\r\n";
+        let sent = exchange(config, huge).await;
+        assert!(sent.starts_with("HTTP/1.1 413 Content Too Large\r\n"), "{}", sent);
+        assert!(!sent.contains("100 Continue"));
     }
-}
\ No newline at end of file
+
+    fn gzip_chunked(content: &[u8], extra_headers: &str) -> Vec<u8> {
+        let encoded = compression::compress(ContentCoding::Gzip, content).unwrap();
+        let mut request = format!("POST /upload HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip, chunked\r\n{}\r\n", extra_headers).into_bytes();
+        for chunk in encoded.chunks(100) {
+            request.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            request.extend_from_slice(chunk);
+            request.extend_from_slice(b"\r\n");
+        }
+        request.extend_from_slice(b"0\r\n\r\n");
+        request
+    }
+
+    #[tokio::test]
+    async fn transfer_codings_come_off_before_the_digest() {
+        let content = b"checked after the gzip comes off ".repeat(100);
+        let digest = format!("Content-Digest: {}\r\n", digest::content_digest(DigestAlgorithm::Sha256, &content));
+        let sent = exchange(ConnectionConfig::default(), &gzip_chunked(&content, &digest)).await;
+        assert!(sent.starts_with("HTTP/1.1 200 OK\r\n"), "{}", sent);
+        assert!(sent.contains(&format!("received {} bytes", content.len())), "{}", sent);
+
+        let wrong = format!("Content-Digest: {}\r\n", digest::content_digest(DigestAlgorithm::Sha256, b"something else"));
+        let sent = exchange(ConnectionConfig::default(), &gzip_chunked(&content, &wrong)).await;
+        assert!(sent.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", sent);
+
+        // the decoded-size cap holds for transfer codings as well
+        let config = ConnectionConfig { max_decoded_body: Some(content.len() - 1), ..ConnectionConfig::default() };
+        let sent = exchange(config, &gzip_chunked(&content, "")).await;
+        assert!(sent.starts_with("HTTP/1.1 413 Content Too Large\r\n"), "{}", sent);
+    }
+
+    #[tokio::test]
+    async fn encoded_bodies_decode_as_they_stream() {
+        let config = ConnectionConfig { max_decoded_body: Some(4096), ..ConnectionConfig::default() };
+        let upload = |body: &[u8]| {
+            let encoded = compression::compress(ContentCoding::Gzip, body).unwrap();
+            let mut request = format!("POST /upload HTTP/1.1\r\nHost: a\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n", encoded.len()).into_bytes();
+            request.extend_from_slice(&encoded);
+            request
+        };
+
+        let sent = exchange(config.clone(), &upload(&[b'x'; 4096])).await;
+        assert!(sent.contains("\r\n\r\nreceived 4096 bytes, sha256 "), "{}", sent);
+        let sent = exchange(config, &upload(&[b'x'; 4097])).await;
+        assert!(sent.starts_with("HTTP/1.1 413 Content Too Large\r\n"), "{}", sent);
+    }
+}
@@ -0,0 +1,184 @@
+use std::borrow::Cow;
+use std::fmt;
+
+macro_rules! statuses {
+    ($($variant:ident = $code:literal, $reason:literal;)*) => {
+        /// A response status. Every code in the IANA registry has its own
+        /// variant; anything else can be sent with `Custom`.
+        #[derive(Debug, Clone)]
+        pub enum HttpStatus {
+            $($variant,)*
+            Custom(u16, Cow<'static, str>),
+        }
+
+        impl HttpStatus {
+            pub fn code(&self) -> u16 {
+                match self {
+                    $(HttpStatus::$variant => $code,)*
+                    HttpStatus::Custom(code, _) => *code,
+                }
+            }
+
+            pub fn reason(&self) -> &str {
+                match self {
+                    $(HttpStatus::$variant => $reason,)*
+                    HttpStatus::Custom(_, reason) => reason,
+                }
+            }
+
+            /// The registered status for `code`, if there is one.
+            pub fn from_code(code: u16) -> Option<Self> {
+                match code {
+                    $($code => Some(HttpStatus::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+statuses! {
+    Continue = 100, "Continue";
+    SwitchingProtocols = 101, "Switching Protocols";
+    Processing = 102, "Processing";
+    EarlyHints = 103, "Early Hints";
+
+    Ok = 200, "OK";
+    Created = 201, "Created";
+    Accepted = 202, "Accepted";
+    NonAuthoritativeInformation = 203, "Non-Authoritative Information";
+    NoContent = 204, "No Content";
+    ResetContent = 205, "Reset Content";
+    PartialContent = 206, "Partial Content";
+    MultiStatus = 207, "Multi-Status";
+    AlreadyReported = 208, "Already Reported";
+    ImUsed = 226, "IM Used";
+
+    MultipleChoices = 300, "Multiple Choices";
+    MovedPermanently = 301, "Moved Permanently";
+    Found = 302, "Found";
+    SeeOther = 303, "See Other";
+    NotModified = 304, "Not Modified";
+    UseProxy = 305, "Use Proxy";
+    TemporaryRedirect = 307, "Temporary Redirect";
+    PermanentRedirect = 308, "Permanent Redirect";
+
+    BadRequest = 400, "Bad Request";
+    Unauthorized = 401, "Unauthorized";
+    PaymentRequired = 402, "Payment Required";
+    Forbidden = 403, "Forbidden";
+    NotFound = 404, "Not Found";
+    MethodNotAllowed = 405, "Method Not Allowed";
+    NotAcceptable = 406, "Not Acceptable";
+    ProxyAuthenticationRequired = 407, "Proxy Authentication Required";
+    RequestTimeout = 408, "Request Timeout";
+    Conflict = 409, "Conflict";
+    Gone = 410, "Gone";
+    LengthRequired = 411, "Length Required";
+    PreconditionFailed = 412, "Precondition Failed";
+    ContentTooLarge = 413, "Content Too Large";
+    UriTooLong = 414, "URI Too Long";
+    UnsupportedMediaType = 415, "Unsupported Media Type";
+    RangeNotSatisfiable = 416, "Range Not Satisfiable";
+    ExpectationFailed = 417, "Expectation Failed";
+    MisdirectedRequest = 421, "Misdirected Request";
+    UnprocessableContent = 422, "Unprocessable Content";
+    Locked = 423, "Locked";
+    FailedDependency = 424, "Failed Dependency";
+    TooEarly = 425, "Too Early";
+    UpgradeRequired = 426, "Upgrade Required";
+    PreconditionRequired = 428, "Precondition Required";
+    TooManyRequests = 429, "Too Many Requests";
+    RequestHeaderFieldsTooLarge = 431, "Request Header Fields Too Large";
+    UnavailableForLegalReasons = 451, "Unavailable For Legal Reasons";
+
+    InternalServerError = 500, "Internal Server Error";
+    NotImplemented = 501, "Not Implemented";
+    BadGateway = 502, "Bad Gateway";
+    ServiceUnavailable = 503, "Service Unavailable";
+    GatewayTimeout = 504, "Gateway Timeout";
+    HttpVersionNotSupported = 505, "HTTP Version Not Supported";
+    VariantAlsoNegotiates = 506, "Variant Also Negotiates";
+    InsufficientStorage = 507, "Insufficient Storage";
+    LoopDetected = 508, "Loop Detected";
+    NotExtended = 510, "Not Extended";
+    NetworkAuthenticationRequired = 511, "Network Authentication Required";
+}
+
+impl HttpStatus {
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.code())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.code())
+    }
+
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.code())
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.code())
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.code())
+    }
+
+    /// Whether this can go on a status line as is: a three digit code and a
+    /// reason phrase without control characters.
+    pub fn is_valid(&self) -> bool {
+        (100..=999).contains(&self.code())
+            && self.reason().bytes().all(|b| b == b'\t' || (b' '..=b'~').contains(&b) || b >= 0x80)
+    }
+}
+
+// statuses are the same if their codes are; a `Custom(404, ..)` is a NotFound
+impl PartialEq for HttpStatus {
+    fn eq(&self, other: &Self) -> bool {
+        self.code() == other.code()
+    }
+}
+
+impl Eq for HttpStatus {}
+
+impl From<u16> for HttpStatus {
+    fn from(code: u16) -> Self {
+        HttpStatus::from_code(code).unwrap_or(HttpStatus::Custom(code, Cow::Borrowed("")))
+    }
+}
+
+/// Formats as `200 OK`; the writer puts the protocol version in front.
+impl fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn codes_and_classes() {
+        assert_eq!(HttpStatus::NotFound.code(), 404);
+        assert_eq!(HttpStatus::Found.to_string(), "302 Found");
+        assert_eq!(HttpStatus::from_code(416), Some(HttpStatus::RangeNotSatisfiable));
+        assert_eq!(HttpStatus::from_code(299), None);
+        assert_eq!(HttpStatus::from(404), HttpStatus::NotFound);
+        assert_eq!(HttpStatus::Custom(404, "Nope".into()), HttpStatus::NotFound);
+
+        let custom = HttpStatus::Custom(599, "Network Connect Timeout".into());
+        assert_eq!(custom.to_string(), "599 Network Connect Timeout");
+        assert!(custom.is_server_error() && !custom.is_client_error());
+        assert!(HttpStatus::EarlyHints.is_informational());
+        assert!(HttpStatus::NoContent.is_success());
+        assert!(HttpStatus::PermanentRedirect.is_redirection());
+        assert!(HttpStatus::TooManyRequests.is_client_error());
+
+        assert!(custom.is_valid());
+        assert!(!HttpStatus::Custom(42, "Short".into()).is_valid());
+        assert!(!HttpStatus::Custom(200, "OK\r\nSet-Cookie: x=1".into()).is_valid());
+    }
+}
@@ -0,0 +1,282 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use socket2::{Domain, Socket, Type};
+use tokio::net::{TcpListener, UnixListener};
+
+use crate::listener::Listener;
+
+// systemd passes sockets starting right after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+// the descriptors can only be adopted once, or two listeners would own each
+static LISTEN_FDS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// A listening socket systemd opened for us, with its `FileDescriptorName=`.
+#[derive(Debug)]
+pub struct ActivatedSocket {
+    pub name: Option<String>,
+    pub listener: Listener,
+}
+
+/// The sockets passed with `LISTEN_FDS`, taken out of the environment but
+/// not yet listeners, which needs the runtime.
+#[derive(Debug)]
+pub struct ListenFds(Vec<(OwnedFd, Option<String>)>);
+
+impl ListenFds {
+    /// Makes a listener of each socket, in the order systemd gave them.
+    pub fn into_sockets(self) -> io::Result<Vec<ActivatedSocket>> {
+        self.0.into_iter()
+            .map(|(fd, name)| Ok(ActivatedSocket { name, listener: adopt_listener(fd)? }))
+            .collect()
+    }
+}
+
+/// Takes over the sockets passed with `LISTEN_FDS`. Empty when the process
+/// wasn't socket activated, or when they've already been taken.
+///
+/// Like `sd_listen_fds(1)`, this removes the variables so child processes
+/// don't think the sockets are theirs.
+///
+/// # Safety
+///
+/// Changing the environment races with any other thread reading it, so this
+/// has to be called while the process has a single thread: in `main` before
+/// the runtime is built.
+pub unsafe fn listen_fds() -> io::Result<ListenFds> {
+    let fds = take_listen_env(
+        |name| {
+            let value = std::env::var(name).ok();
+            // SAFETY: the caller guarantees no other thread is running
+            unsafe { std::env::remove_var(name) };
+            value
+        },
+        std::process::id(),
+    )?;
+    if fds.is_empty() || LISTEN_FDS_TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(ListenFds(Vec::new()));
+    }
+    Ok(ListenFds(fds.into_iter()
+        // SAFETY: systemd handed these descriptors to this process, and
+        // LISTEN_FDS_TAKEN makes sure nothing else in it takes them
+        .map(|(fd, name)| (unsafe { OwnedFd::from_raw_fd(fd) }, name))
+        .collect()))
+}
+
+// Takes every LISTEN_* variable out of the environment through `take`, ours
+// or not, and works out which descriptors they name.
+fn take_listen_env(mut take: impl FnMut(&str) -> Option<String>, own_pid: u32) -> io::Result<Vec<(RawFd, Option<String>)>> {
+    let [pid, fds, names] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"].map(&mut take);
+    parse_listen_env(pid.as_deref(), fds.as_deref(), names.as_deref(), own_pid)
+}
+
+// Works out which descriptors are ours from the environment. A LISTEN_PID
+// that isn't us means the variables were inherited from a parent.
+fn parse_listen_env(pid: Option<&str>, fds: Option<&str>, names: Option<&str>, own_pid: u32) -> io::Result<Vec<(RawFd, Option<String>)>> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(Vec::new());
+    };
+    if pid.trim().parse::<u32>().ok() != Some(own_pid) {
+        return Ok(Vec::new());
+    }
+    let count = fds.trim().parse::<RawFd>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("LISTEN_FDS isn't a count: '{}'", fds)))?;
+    let mut names = names.map(|names| names.split(':')).into_iter().flatten();
+    Ok((0..count)
+        .map(|i| (LISTEN_FDS_START + i, names.next().filter(|name| !name.is_empty()).map(str::to_string)))
+        .collect())
+}
+
+// Wraps a listening socket we've been given in the right kind of Listener.
+// Its path, if it has one, belongs to systemd and is left alone on drop.
+fn adopt_listener(fd: OwnedFd) -> io::Result<Listener> {
+    let socket = Socket::from(fd);
+    if socket.r#type()? != Type::STREAM {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, format!("fd {} isn't a stream socket", socket.as_raw_fd())));
+    }
+    // inherited descriptors come without close-on-exec, and anything we spawn shouldn't keep them
+    socket.set_cloexec(true)?;
+    socket.set_nonblocking(true)?;
+    if socket.local_addr()?.domain() == Domain::UNIX {
+        Ok(Listener::Unix(UnixListener::from_std(socket.into())?, None))
+    } else {
+        Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+    }
+}
+
+/// Sends service state changes to systemd over `NOTIFY_SOCKET`, for units
+/// with `Type=notify` and, with `WatchdogSec=`, to keep the watchdog fed.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    path: PathBuf,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// A notifier sending to the datagram socket at `path`; an abstract
+    /// socket name starts with `@`.
+    pub fn new(path: impl Into<PathBuf>) -> io::Result<Self> {
+        Ok(Self { socket: UnixDatagram::unbound()?, path: path.into(), watchdog: None })
+    }
+
+    /// The notifier systemd set up through the environment, if any, with the
+    /// watchdog interval it asked for.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return Ok(None);
+        };
+        let watchdog_pid = std::env::var("WATCHDOG_PID").ok();
+        let watchdog = std::env::var("WATCHDOG_USEC").ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| *usec > 0)
+            .filter(|_| watchdog_pid.is_none_or(|pid| pid.parse::<u32>().ok() == Some(std::process::id())))
+            .map(Duration::from_micros);
+        Ok(Some(Self { watchdog, ..Self::new(path)? }))
+    }
+
+    /// Feeds the watchdog every `interval`, or not at all with `None`.
+    pub fn with_watchdog(mut self, interval: Option<Duration>) -> Self {
+        self.watchdog = interval;
+        self
+    }
+
+    /// How often systemd expects `WATCHDOG=1`, if it does.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// Sends newline-separated `KEY=value` assignments as they are.
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        let path = self.path.as_os_str().as_encoded_bytes();
+        let sent = match path.strip_prefix(b"@") {
+            Some(name) => self.send_abstract(name, state)?,
+            None => self.socket.send_to(state.as_bytes(), &self.path)?,
+        };
+        if sent != state.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "notification was cut short"));
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_abstract(&self, name: &[u8], state: &str) -> io::Result<usize> {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        self.socket.send_to_addr(state.as_bytes(), &addr)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn send_abstract(&self, _name: &[u8], _state: &str) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn ready(&self) -> io::Result<()> {
+        self.notify("READY=1")
+    }
+
+    pub fn stopping(&self) -> io::Result<()> {
+        self.notify("STOPPING=1")
+    }
+
+    pub fn watchdog(&self) -> io::Result<()> {
+        self.notify("WATCHDOG=1")
+    }
+
+    /// Sets the one-line status `systemctl status` shows.
+    pub fn status(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("STATUS={}", status.replace('\n', " ")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn listen_env() {
+        assert!(parse_listen_env(None, Some("2"), None, 42).unwrap().is_empty());
+        assert!(parse_listen_env(Some("41"), Some("2"), None, 42).unwrap().is_empty());
+        assert!(parse_listen_env(Some("42"), Some("two"), None, 42).is_err());
+        assert_eq!(
+            parse_listen_env(Some("42"), Some("3"), Some("http::admin"), 42).unwrap(),
+            [(3, Some("http".to_string())), (4, None), (5, Some("admin".to_string()))],
+        );
+    }
+
+    #[test]
+    fn listen_env_is_cleared() {
+        let env = || HashMap::from([("LISTEN_PID", "41"), ("LISTEN_FDS", "2"), ("LISTEN_FDNAMES", "http:admin"), ("HOME", "/root")]);
+        // taken whether or not the sockets turn out to be ours
+        for own_pid in [41, 42] {
+            let mut env = env();
+            let fds = take_listen_env(|name| env.remove(name).map(str::to_string), own_pid).unwrap();
+            assert_eq!(fds.len(), if own_pid == 41 { 2 } else { 0 });
+            assert_eq!(env, HashMap::from([("HOME", "/root")]));
+        }
+    }
+
+    #[tokio::test]
+    async fn adopts_passed_sockets() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        // the way systemd passes it, open across exec
+        socket2::SockRef::from(&tcp).set_cloexec(false).unwrap();
+        let listener = adopt_listener(tcp.into()).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr.to_string());
+        #[cfg(target_os = "linux")]
+        {
+            let Listener::Tcp(tcp) = &listener else { panic!("not a TCP listener: {:?}", listener) };
+            let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", tcp.as_raw_fd())).unwrap();
+            let flags = fdinfo.lines().find_map(|line| line.strip_prefix("flags:")).unwrap();
+            assert_ne!(u32::from_str_radix(flags.trim(), 8).unwrap() & 0o2000000, 0, "not close-on-exec");
+        }
+
+        let path = std::env::temp_dir().join(format!("systemd-test-{}.sock", std::process::id()));
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let listener = adopt_listener(unix.into()).unwrap();
+        assert!(matches!(listener, Listener::Unix(_, None)));
+        drop(listener);
+        // activated sockets aren't ours to clean up
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn notifies_a_fake_systemd() {
+        use crate::listener::Bind;
+        use crate::server::HttpServer;
+
+        let path = std::env::temp_dir().join(format!("notify-test-{}.sock", std::process::id()));
+        let systemd = tokio::net::UnixDatagram::bind(&path).unwrap();
+        let next = async || {
+            let mut received = [0u8; 64];
+            let n = tokio::time::timeout(Duration::from_secs(5), systemd.recv(&mut received)).await.unwrap().unwrap();
+            String::from_utf8_lossy(&received[..n]).into_owned()
+        };
+
+        let notifier = Notifier::new(&path).unwrap().with_watchdog(Some(Duration::from_millis(20)));
+        notifier.status("warming up\nnow").unwrap();
+        assert_eq!(next().await, "STATUS=warming up now");
+
+        let (server, cancel) = HttpServer::bind(&[Bind::Tcp("127.0.0.1:0".parse().unwrap())]).await.unwrap();
+        let mut server = server.with_notifier(notifier);
+        let handle = tokio::spawn(async move { server.listen().await });
+        assert_eq!(next().await, "READY=1");
+        assert_eq!(next().await, "WATCHDOG=1");
+        cancel.send(()).unwrap();
+        handle.await.unwrap().unwrap();
+        let mut last = next().await;
+        while last == "WATCHDOG=1" {
+            last = next().await;
+        }
+        assert_eq!(last, "STOPPING=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use sha2::{Digest, Sha256};
+
+/// Produces a trailer field from the body as it streams past, so nothing
+/// has to be buffered to compute it. Hooks see the content as sent, after
+/// any content-coding, but without the chunked framing.
+pub trait TrailerHook: Send {
+    /// The field name, announced in `Trailer` before the body starts.
+    fn name(&self) -> &str;
+    fn update(&mut self, content: &[u8]);
+    /// The field value, asked for once the body has ended.
+    fn value(&mut self) -> String;
+}
+
+/// Hex SHA-256 of the body.
+#[derive(Debug, Clone)]
+pub struct Sha256Trailer {
+    name: String,
+    hasher: Sha256,
+}
+
+impl Sha256Trailer {
+    pub fn new(name: &str) -> Self {
+        Sha256Trailer { name: name.to_string(), hasher: Sha256::new() }
+    }
+}
+
+impl TrailerHook for Sha256Trailer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn update(&mut self, content: &[u8]) {
+        self.hasher.update(content);
+    }
+
+    fn value(&mut self) -> String {
+        format!("{:x}", std::mem::take(&mut self.hasher).finalize())
+    }
+}
+
+/// Number of body bytes sent.
+#[derive(Debug, Clone)]
+pub struct LengthTrailer {
+    name: String,
+    length: u64,
+}
+
+impl LengthTrailer {
+    pub fn new(name: &str) -> Self {
+        LengthTrailer { name: name.to_string(), length: 0 }
+    }
+}
+
+impl TrailerHook for LengthTrailer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn update(&mut self, content: &[u8]) {
+        self.length += content.len() as u64;
+    }
+
+    fn value(&mut self) -> String {
+        self.length.to_string()
+    }
+}
+
+// fields that frame, route or describe the message can't be deferred to the end of it
+const FORBIDDEN_TRAILERS: [&str; 11] = [
+    "content-length", "transfer-encoding", "trailer", "te", "host", "connection",
+    "content-type", "content-encoding", "content-range", "authorization", "set-cookie",
+];
+
+pub fn is_allowed_trailer(name: &str) -> bool {
+    !FORBIDDEN_TRAILERS.iter().any(|forbidden| forbidden.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hooks_accumulate() {
+        let mut sha = Sha256Trailer::new("X-Content-SHA256");
+        let mut length = LengthTrailer::new("X-Content-Length");
+        for piece in [&b"hel"[..], b"lo"] {
+            sha.update(piece);
+            length.update(piece);
+        }
+        assert_eq!(sha.value(), "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+        assert_eq!(length.value(), "5");
+        assert!(is_allowed_trailer("X-Content-SHA256"));
+        assert!(!is_allowed_trailer("Content-Length"));
+    }
+}